@@ -0,0 +1,439 @@
+extern crate serde_json;
+
+use std;
+use std::collections::HashMap;
+
+use self::serde_json::Value;
+
+// Value of a single quantity reported by a provider
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub provider: &'static str,
+    pub value: f32
+}
+
+// Combines values providers reported for the same quantity into one
+pub trait Aggregator {
+    fn name(&self) -> &'static str;
+
+    // None when there is nothing to combine
+    fn combine(&self, samples: &[Sample]) -> Option<f32>;
+
+    // Samples the combined value is built from, without outliers the strategy drops
+    fn used(&self, samples: &[Sample]) -> Vec<Sample> {
+        finite(samples)
+    }
+}
+
+// Samples with finite values, others can't be ordered or combined
+fn finite(samples: &[Sample]) -> Vec<Sample> {
+    samples.iter().filter(|s| s.value.is_finite()).cloned().collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    Mean,
+    Median,
+    TrimmedMean,
+    WeightedMean,
+    // mean of values left after dropping outliers by median absolute deviation
+    Mad
+}
+
+impl Strategy {
+    pub fn parse(s: &str) -> Option<Strategy> {
+        match s.to_lowercase().as_str() {
+            "mean" => Some(Strategy::Mean),
+            "median" => Some(Strategy::Median),
+            "trimmed" | "trimmed_mean" => Some(Strategy::TrimmedMean),
+            "weighted" | "weighted_mean" => Some(Strategy::WeightedMean),
+            "mad" => Some(Strategy::Mad),
+            _ => None
+        }
+    }
+}
+
+// Standard deviations of temperatures in °C above which
+// the confidence drops to medium and to low
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConfidenceThresholds {
+    pub medium: f32,
+    pub low: f32
+}
+
+impl Default for ConfidenceThresholds {
+    fn default() -> ConfidenceThresholds {
+        ConfidenceThresholds { medium: 1.0, low: 2.5 }
+    }
+}
+
+// Default strategy and per-provider weights of the weighted mean,
+// providers without a weight count as 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregationConfig {
+    pub strategy: Strategy,
+    pub weights: HashMap<String, f32>,
+    pub confidence: ConfidenceThresholds
+}
+
+impl Default for AggregationConfig {
+    fn default() -> AggregationConfig {
+        AggregationConfig { strategy: Strategy::Mean, weights: HashMap::new(), confidence: ConfidenceThresholds::default() }
+    }
+}
+
+// Parses weights given as "owm:1,apixu:0.5"
+pub fn parse_weights(s: &str) -> Result<HashMap<String, f32>, String> {
+    s.split(',').filter(|part| !part.trim().is_empty()).map(|part| {
+        let mut pair = part.splitn(2, ':');
+        let provider = pair.next().unwrap_or("").trim();
+        let weight = pair.next().and_then(|w| w.trim().parse::<f32>().ok()).filter(|w| w.is_finite() && *w >= 0.0);
+
+        match weight {
+            Some(weight) if !provider.is_empty() => Ok((provider.to_owned(), weight)),
+            _ => Err(format!("Invalid weight: {}", part))
+        }
+    }).collect()
+}
+
+pub fn aggregator(strategy: Strategy, config: &AggregationConfig) -> Box<Aggregator> {
+    match strategy {
+        Strategy::Mean => Box::new(Mean),
+        Strategy::Median => Box::new(Median),
+        Strategy::TrimmedMean => Box::new(TrimmedMean { fraction: 0.2 }),
+        Strategy::WeightedMean => Box::new(WeightedMean { weights: config.weights.clone() }),
+        Strategy::Mad => Box::new(MadFilter { threshold: 3.0 })
+    }
+}
+
+// Combines optional values of a quantity, absent ones are skipped
+pub fn combine<I: Iterator<Item = (&'static str, Option<f32>)>>(aggregator: &Aggregator, values: I) -> Option<f32> {
+    let samples: Vec<Sample> = values.filter_map(|(provider, v)| v.map(|value| Sample { provider: provider, value: value })).collect();
+    aggregator.combine(&samples)
+}
+
+pub struct Mean;
+
+impl Aggregator for Mean {
+    fn name(&self) -> &'static str {
+        "mean"
+    }
+
+    fn combine(&self, samples: &[Sample]) -> Option<f32> {
+        let samples = finite(samples);
+        if samples.is_empty() {
+            return None;
+        }
+        Some(samples.iter().map(|s| s.value).sum::<f32>() / samples.len() as f32)
+    }
+}
+
+pub struct Median;
+
+impl Aggregator for Median {
+    fn name(&self) -> &'static str {
+        "median"
+    }
+
+    fn combine(&self, samples: &[Sample]) -> Option<f32> {
+        median(samples.iter().map(|s| s.value).collect())
+    }
+}
+
+// Mean of values left after dropping `fraction` of the lowest and the highest ones,
+// at least one from each side once there are three values
+pub struct TrimmedMean {
+    pub fraction: f32
+}
+
+impl Aggregator for TrimmedMean {
+    fn name(&self) -> &'static str {
+        "trimmed_mean"
+    }
+
+    fn combine(&self, samples: &[Sample]) -> Option<f32> {
+        Mean.combine(&self.used(samples))
+    }
+
+    fn used(&self, samples: &[Sample]) -> Vec<Sample> {
+        let mut sorted = finite(samples);
+        sorted.sort_by(|a, b| a.value.partial_cmp(&b.value).unwrap());
+
+        let n = sorted.len();
+        let trimmed = if n >= 3 { ((n as f32 * self.fraction) as usize).max(1) } else { 0 };
+        sorted[trimmed..n - trimmed].to_vec()
+    }
+}
+
+pub struct WeightedMean {
+    pub weights: HashMap<String, f32>
+}
+
+impl Aggregator for WeightedMean {
+    fn name(&self) -> &'static str {
+        "weighted_mean"
+    }
+
+    fn combine(&self, samples: &[Sample]) -> Option<f32> {
+        let used = self.used(samples);
+        let total: f32 = used.iter().map(|s| self.weight(s)).sum();
+
+        if total <= 0.0 {
+            return Mean.combine(&used);
+        }
+        Some(used.iter().map(|s| s.value * self.weight(s)).sum::<f32>() / total)
+    }
+
+    // providers with zero weight only count when there is nobody else
+    fn used(&self, samples: &[Sample]) -> Vec<Sample> {
+        let samples = finite(samples);
+        let weighted: Vec<Sample> = samples.iter().filter(|s| self.weight(s) > 0.0).cloned().collect();
+        if weighted.is_empty() { samples } else { weighted }
+    }
+}
+
+impl WeightedMean {
+    fn weight(&self, sample: &Sample) -> f32 {
+        self.weights.get(sample.provider).cloned().unwrap_or(1.0)
+    }
+}
+
+// Drops values further than `threshold` scaled median absolute deviations
+// from the median, then takes the mean of the rest
+pub struct MadFilter {
+    pub threshold: f32
+}
+
+// Scales MAD to be comparable with the standard deviation of normally distributed values
+const MAD_SCALE: f32 = 1.4826;
+// Smallest MAD values are filtered with, so that values agreeing
+// within a fraction of a degree aren't dropped when the MAD is 0
+const MIN_MAD: f32 = 0.5;
+
+impl Aggregator for MadFilter {
+    fn name(&self) -> &'static str {
+        "mad"
+    }
+
+    fn combine(&self, samples: &[Sample]) -> Option<f32> {
+        Mean.combine(&self.used(samples))
+    }
+
+    fn used(&self, samples: &[Sample]) -> Vec<Sample> {
+        let samples = finite(samples);
+        let center = match median(samples.iter().map(|s| s.value).collect()) {
+            Some(center) => center,
+            None => return vec![]
+        };
+        let mad = median(samples.iter().map(|s| (s.value - center).abs()).collect()).unwrap_or(0.0).max(MIN_MAD);
+
+        samples.into_iter()
+            .filter(|s| (s.value - center).abs() <= self.threshold * MAD_SCALE * mad)
+            .collect()
+    }
+}
+
+// How far apart values of the providers are
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Spread {
+    pub min: f32,
+    pub max: f32,
+    pub stddev: f32,
+    pub count: usize
+}
+
+// How much the aggregate can be relied on, judging by agreement of providers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Confidence {
+    High,
+    Medium,
+    Low
+}
+
+impl Confidence {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Confidence::High => "high",
+            Confidence::Medium => "medium",
+            Confidence::Low => "low"
+        }
+    }
+}
+
+impl Spread {
+    // Spread of the samples an aggregate is built from, see `Aggregator::used`
+    pub fn of(samples: &[Sample]) -> Option<Spread> {
+        let samples = &finite(samples)[..];
+        let mean = Mean.combine(samples)?;
+        let variance = samples.iter().map(|s| (s.value - mean).powi(2)).sum::<f32>() / samples.len() as f32;
+
+        Some(Spread {
+            min: samples.iter().map(|s| s.value).fold(std::f32::INFINITY, f32::min),
+            max: samples.iter().map(|s| s.value).fold(std::f32::NEG_INFINITY, f32::max),
+            stddev: variance.sqrt(),
+            count: samples.len()
+        })
+    }
+
+    // Confidence for a spread of temperatures in °C: a single provider can't be
+    // cross-checked, several ones are trusted while they are within the thresholds
+    pub fn temperature_confidence(&self, thresholds: &ConfidenceThresholds) -> Confidence {
+        if self.count < 2 || self.stddev > thresholds.low {
+            Confidence::Low
+        } else if self.stddev > thresholds.medium {
+            Confidence::Medium
+        } else {
+            Confidence::High
+        }
+    }
+
+    // Converts with a linear unit conversion, the deviation is a difference
+    // so it isn't shifted along with the values
+    pub fn convert<F: Fn(f32) -> f32>(&self, convert: F) -> Spread {
+        Spread {
+            min: convert(self.min),
+            max: convert(self.max),
+            stddev: convert(self.stddev) - convert(0.0),
+            count: self.count
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "min": self.min,
+            "max": self.max,
+            "stddev": self.stddev,
+            "count": self.count
+        })
+    }
+}
+
+fn median(values: Vec<f32>) -> Option<f32> {
+    let mut values: Vec<f32> = values.into_iter().filter(|v| v.is_finite()).collect();
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let middle = values.len() / 2;
+    if values.len() % 2 == 0 {
+        Some((values[middle - 1] + values[middle]) / 2.0)
+    } else {
+        Some(values[middle])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(values: &[(&'static str, f32)]) -> Vec<Sample> {
+        values.iter().map(|&(provider, value)| Sample { provider: provider, value: value }).collect()
+    }
+
+    #[test]
+    fn strategies_handle_outliers_differently() {
+        // one provider reporting Kelvin instead of Celsius
+        let s = samples(&[("owm", -14.0), ("apixu", -12.0), ("weatherbit", 259.0)]);
+        let config = AggregationConfig::default();
+        let combine = |strategy| aggregator(strategy, &config).combine(&s).unwrap();
+
+        assert_eq!(combine(Strategy::Mean), 77.666664);
+        assert_eq!(combine(Strategy::Median), -12.0);
+        assert_eq!(combine(Strategy::TrimmedMean), -12.0);
+        assert_eq!(combine(Strategy::Mad), -13.0);
+    }
+
+    #[test]
+    fn weighted_mean_uses_provider_weights() {
+        let s = samples(&[("owm", -14.0), ("apixu", -10.0)]);
+        let mut weights = HashMap::new();
+        weights.insert("owm".to_owned(), 3.0);
+
+        assert_eq!(WeightedMean { weights: weights.clone() }.combine(&s), Some(-13.0));
+
+        weights.insert("apixu".to_owned(), 0.0);
+        weights.insert("owm".to_owned(), 0.0);
+        assert_eq!(WeightedMean { weights: weights }.combine(&s), Some(-12.0));
+    }
+
+    #[test]
+    fn aggregators_combine_nothing_into_none() {
+        let config = AggregationConfig::default();
+
+        for strategy in vec![Strategy::Mean, Strategy::Median, Strategy::TrimmedMean, Strategy::WeightedMean, Strategy::Mad] {
+            assert_eq!(aggregator(strategy, &config).combine(&[]), None);
+        }
+        assert_eq!(combine(&Median, vec![("owm", None), ("apixu", Some(-12.0))].into_iter()), Some(-12.0));
+    }
+
+    #[test]
+    fn spread_describes_agreement() {
+        let agreeing = Spread::of(&samples(&[("owm", -14.0), ("apixu", -13.0), ("weatherbit", -13.5)])).unwrap();
+        let disagreeing = Spread::of(&samples(&[("owm", -15.0), ("apixu", -5.0), ("weatherbit", 5.0)])).unwrap();
+        let single = Spread::of(&samples(&[("owm", -14.0)])).unwrap();
+
+        assert_eq!((disagreeing.min, disagreeing.max, disagreeing.count), (-15.0, 5.0, 3));
+        assert!((disagreeing.stddev - 8.165).abs() < 0.001);
+
+        let thresholds = ConfidenceThresholds::default();
+        assert_eq!(agreeing.temperature_confidence(&thresholds), Confidence::High);
+        assert_eq!(disagreeing.temperature_confidence(&thresholds), Confidence::Low);
+        assert_eq!(single.temperature_confidence(&thresholds), Confidence::Low);
+        assert_eq!(disagreeing.temperature_confidence(&ConfidenceThresholds { medium: 5.0, low: 10.0 }), Confidence::Medium);
+        assert_eq!(Spread::of(&[]), None);
+
+        let fahrenheit = disagreeing.convert(|c| c * 1.8 + 32.0);
+        assert_eq!((fahrenheit.min, fahrenheit.max), (5.0, 41.0));
+        assert!((fahrenheit.stddev - 14.697).abs() < 0.001);
+    }
+
+    #[test]
+    fn spread_leaves_out_dropped_outliers() {
+        let s = samples(&[("owm", -14.0), ("apixu", -13.0), ("weatherbit", 259.0)]);
+        let config = AggregationConfig::default();
+        let spread = |strategy| Spread::of(&aggregator(strategy, &config).used(&s)).unwrap();
+
+        assert_eq!(spread(Strategy::Mad).count, 2);
+        assert_eq!(spread(Strategy::Mad).temperature_confidence(&config.confidence), Confidence::High);
+        assert_eq!(spread(Strategy::TrimmedMean).count, 1);
+        assert_eq!(spread(Strategy::Mean).count, 3);
+        assert_eq!(spread(Strategy::Mean).temperature_confidence(&config.confidence), Confidence::Low);
+    }
+
+    #[test]
+    fn mad_keeps_close_values_when_most_agree() {
+        let mad = aggregator(Strategy::Mad, &AggregationConfig::default());
+
+        let close = samples(&[("owm", -13.0), ("apixu", -13.0), ("weatherbit", -12.9)]);
+        assert_eq!(mad.used(&close).len(), 3);
+
+        let kelvin = samples(&[("owm", -13.0), ("apixu", -13.0), ("weatherbit", 260.15)]);
+        assert_eq!(mad.used(&kelvin).len(), 2);
+    }
+
+    #[test]
+    fn aggregators_skip_non_finite_values() {
+        let s = samples(&[("owm", -14.0), ("apixu", std::f32::NAN), ("weatherbit", -12.0)]);
+        let config = AggregationConfig::default();
+
+        for strategy in vec![Strategy::Mean, Strategy::Median, Strategy::TrimmedMean, Strategy::WeightedMean, Strategy::Mad] {
+            assert_eq!(aggregator(strategy, &config).combine(&s), Some(-13.0));
+        }
+        assert_eq!(Spread::of(&s).unwrap().count, 2);
+    }
+
+    #[test]
+    fn it_parses_strategies_and_weights() {
+        assert_eq!(Strategy::parse("Median"), Some(Strategy::Median));
+        assert_eq!(Strategy::parse("trimmed"), Some(Strategy::TrimmedMean));
+        assert_eq!(Strategy::parse("mode"), None);
+
+        let weights = parse_weights("owm:1, apixu:0.5").unwrap();
+        assert_eq!(weights.get("apixu"), Some(&0.5));
+        assert_eq!(weights.len(), 2);
+        assert_eq!(parse_weights("owm"), Err("Invalid weight: owm".to_owned()));
+        assert_eq!(parse_weights("owm:inf"), Err("Invalid weight: owm:inf".to_owned()));
+        assert_eq!(parse_weights("owm:NaN"), Err("Invalid weight: owm:NaN".to_owned()));
+    }
+}
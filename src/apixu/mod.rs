@@ -2,87 +2,173 @@
 
 extern crate futures;
 extern crate hyper;
-extern crate tokio_core;
 extern crate serde_json;
-extern crate itertools;
 
-use std::io;
 use self::serde_json::Value;
-use self::futures::{Future};
 
-use self::tokio_core::reactor::{Handle};
-
-use async_request::async_json_request;
-use async_request::Result;
+use async_request::{async_api_request, ApiFuture, AsyncJsonResponse};
 use async_request::error::ApiError;
-
-use self::itertools::EitherOrBoth::{Both};
-use self::itertools::Itertools;
+use async_request::client::HttpClient;
+use keys::KeyRing;
+use provider::{WeatherProvider, Capabilities, unsupported_location};
+use query::encode_component;
+use location::{Location, Coordinates};
+use observation::{CurrentObservation, json_f32, json_string};
+use forecast::{Forecast, ForecastDate, DailyForecast, utc_offset};
+use geocoding::{Geocoder, CanonicalLocation};
 
 #[cfg(not(test))]
-const API_ROOT: &'static str = "http://api.apixu.com/v1";
-
-pub fn current(handle: &Handle, q: &str, api_key: &str) -> Box<Future<Item = Result<f32>, Error = hyper::Error>> {
-    let url = format!("{api_root}/current.json?key={key}&q={loc}", loc=q, key=api_key, api_root=API_ROOT);
-
-    let resp = async_json_request(handle, &url).and_then(|s| {
-        if s.status == hyper::StatusCode::BadRequest {
-            return Ok(Err(ApiError::LocationNotFound));
-        }
-
-        if s.body.is_none() {
-            return Ok(Err(ApiError::Other));
-        }
-
-        let body = s.body.unwrap();
-        let json_temp: Value = body["current"]["temp_c"].clone();
-        let temp = serde_json::from_value::<f32>(json_temp).map_err(|e|
-            io::Error::new(
-                io::ErrorKind::Other,
-                e
-            )
-        )?;
-        Ok(Ok(temp))
-    }).or_else(|_s|
-        Ok(Err(ApiError::Other))
-    );
-
-    Box::new(resp)
+const API_ROOT: &'static str = "https://api.apixu.com/v1";
+
+pub struct Apixu {
+    keys: KeyRing
+}
+
+impl Apixu {
+    pub fn new(keys: KeyRing) -> Apixu {
+        Apixu { keys: keys }
+    }
+}
+
+impl WeatherProvider for Apixu {
+    fn name(&self) -> &'static str {
+        "apixu"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { current: true, forecast: true }
+    }
+
+    fn supports(&self, location: &Location) -> bool {
+        location_params(location).is_some()
+    }
+
+    fn current(&self, client: &HttpClient, location: &Location) -> ApiFuture<CurrentObservation> {
+        current(client, location, &self.keys)
+    }
+
+    fn forecast(&self, client: &HttpClient, location: &Location) -> ApiFuture<Forecast> {
+        forecast(client, location, &self.keys)
+    }
 }
 
-pub fn forecast(handle: &Handle, q: &str, api_key: &str) -> Box<Future<Item = Result<Vec<Option<f32>>>, Error = hyper::Error>> {
-    let url = format!("{api_root}/forecast.json?key={key}&q={loc}&days=5", loc=q, key=api_key, api_root=API_ROOT);
-
-    let resp = async_json_request(handle, &url).and_then(|s| {
-        if s.status == hyper::StatusCode::BadRequest {
-            return Ok(Err(ApiError::LocationNotFound));
-        }
-
-        if s.body.is_none() {
-            return Ok(Err(ApiError::Other));
-        }
-
-        let body = s.body.unwrap();
-        let fcast: Value = body["forecast"]["forecastday"].clone();
-        let json_temps: Vec<Value> = serde_json::from_value(fcast).map_err(|e|
-            io::Error::new(
-                io::ErrorKind::Other,
-                e
-            )
-        )?;
-        let temps = json_temps.into_iter().map(|v|
-            serde_json::from_value::<f32>(v["day"]["avgtemp_c"].clone()).ok()
-        ).zip_longest(0..5).map(|v| match v {
-            Both(Some(t), _) => Some(t),
-            _ => None
+pub fn current(client: &HttpClient, location: &Location, keys: &KeyRing) -> ApiFuture<CurrentObservation> {
+    let loc = match location_params(location) {
+        Some(params) => params,
+        None => return unsupported_location()
+    };
+    let url = move |key: &str| format!("{api_root}/current.json?key={key}&{loc}", loc=loc, key=encode_component(key), api_root=API_ROOT);
+
+    async_api_request(client, keys, url, api_error, |body| {
+        let current = &body["current"];
+        let temp = serde_json::from_value::<f32>(current["temp_c"].clone())?;
+
+        Ok(CurrentObservation {
+            temperature: temp,
+            humidity: json_f32(&current["humidity"]),
+            pressure: json_f32(&current["pressure_mb"]),
+            wind_speed: json_f32(&current["wind_kph"]).map(|kph| kph / 3.6),
+            wind_direction: json_f32(&current["wind_degree"]),
+            cloud_cover: json_f32(&current["cloud"]),
+            visibility: json_f32(&current["vis_km"]),
+            precipitation: json_f32(&current["precip_mm"]),
+            condition: json_string(&current["condition"]["text"]),
+            observed_at: current["last_updated_epoch"].as_i64(),
+            coordinates: Coordinates::from_json(&body["location"]["lat"], &body["location"]["lon"])
+        })
+    })
+}
+
+pub fn forecast(client: &HttpClient, location: &Location, keys: &KeyRing) -> ApiFuture<Forecast> {
+    let loc = match location_params(location) {
+        Some(params) => params,
+        None => return unsupported_location()
+    };
+    let url = move |key: &str| format!("{api_root}/forecast.json?key={key}&{loc}&days=5", loc=loc, key=encode_component(key), api_root=API_ROOT);
+
+    async_api_request(client, keys, url, api_error, |body| {
+        let json_days: Vec<Value> = serde_json::from_value(body["forecast"]["forecastday"].clone())?;
+        let days = json_days.into_iter().filter_map(|v| {
+            let date = v["date"].as_str().and_then(ForecastDate::parse)?;
+            let day = &v["day"];
+            Some((date, DailyForecast {
+                temperature: json_f32(&day["avgtemp_c"]),
+                min_temperature: json_f32(&day["mintemp_c"]),
+                max_temperature: json_f32(&day["maxtemp_c"]),
+                precipitation: json_f32(&day["totalprecip_mm"]),
+                precipitation_probability: None,
+                wind_speed: json_f32(&day["maxwind_kph"]).map(|kph| kph / 3.6),
+                condition: json_string(&day["condition"]["text"])
+            }))
         }).collect();
 
-        Ok(Ok(temps))
-    }).or_else(|_s|
-        Ok(Err(ApiError::Other))
-    );
+        // dates are already local to the location
+        let place = &body["location"];
+        Ok(Forecast {
+            timezone: json_string(&place["tz_id"]),
+            utc_offset: place["localtime"].as_str().and_then(|local| place["localtime_epoch"].as_i64().and_then(|ts| utc_offset(local, ts))),
+            coordinates: Coordinates::from_json(&body["location"]["lat"], &body["location"]["lon"]),
+            days: days
+        })
+    })
+}
+
+impl Geocoder for Apixu {
+    fn resolve(&self, client: &HttpClient, location: &Location) -> ApiFuture<CanonicalLocation> {
+        resolve(client, location, &self.keys)
+    }
+}
+
+// Looks the location up with the timezone api, which reports the place
+// apixu resolved the query to along with its timezone
+pub fn resolve(client: &HttpClient, location: &Location, keys: &KeyRing) -> ApiFuture<CanonicalLocation> {
+    let loc = match location_params(location) {
+        Some(params) => params,
+        None => return unsupported_location()
+    };
+    let url = move |key: &str| format!("{api_root}/timezone.json?key={key}&{loc}", loc=loc, key=encode_component(key), api_root=API_ROOT);
+
+    async_api_request(client, keys, url, api_error, |body| {
+        let place = &body["location"];
+        let name = serde_json::from_value::<String>(place["name"].clone())?;
+        let lat = serde_json::from_value::<f64>(place["lat"].clone())?;
+        let lon = serde_json::from_value::<f64>(place["lon"].clone())?;
+
+        Ok(CanonicalLocation {
+            name: name,
+            region: json_string(&place["region"]),
+            country: json_string(&place["country"]),
+            coordinates: Coordinates::new(lat, lon),
+            timezone: json_string(&place["tz_id"])
+        })
+    })
+}
 
-    Box::new(resp)
+// Apixu tells errors apart by the code in the body: 1006 is an unknown place,
+// 1002, 2006 and 2008 are missing, invalid and disabled keys, 2007 is the
+// exceeded monthly quota and 9999 its own failure
+fn api_error(response: &AsyncJsonResponse) -> Option<ApiError> {
+    let code = response.body.as_ref().and_then(|b| b["error"]["code"].as_i64());
+
+    match (response.status, code) {
+        (_, Some(1006)) | (hyper::StatusCode::BadRequest, None) => Some(ApiError::LocationNotFound),
+        (_, Some(1002)) | (_, Some(2006)) | (_, Some(2008)) => Some(ApiError::InvalidApiKey),
+        (_, Some(2007)) => Some(ApiError::QuotaExceeded),
+        (status, Some(9999)) => Some(ApiError::UpstreamServerError(status.as_u16())),
+        _ => None
+    }
+}
+
+// Location as apixu query parameters, apixu takes every form of location in `q`
+fn location_params(location: &Location) -> Option<String> {
+    match *location {
+        Location::Name(ref name) => Some(format!("q={}", encode_component(name))),
+        Location::Coordinates { lat, lon } => Some(format!("q={},{}", lat, lon)),
+        Location::PostalCode { ref code, country: Some(ref country) } => Some(format!("q={},{}", encode_component(code), encode_component(country))),
+        Location::PostalCode { ref code, country: None } => Some(format!("q={}", encode_component(code))),
+        Location::Ip(ref ip) => Some(format!("q={}", encode_component(&ip.to_string()))),
+        Location::Auto => None
+    }
 }
 
 #[cfg(test)]
@@ -94,7 +180,13 @@ const API_ROOT: &'static str = mockito::SERVER_URL;
 #[cfg(test)]
 mod tests {
     use super::*;
+    use self::futures::Future;
     use self::mockito::{mock, Matcher};
+    use keys::KeysConfig;
+
+    fn key(key: &str) -> KeyRing {
+        KeyRing::new(&[key.to_owned()], KeysConfig::default())
+    }
 
     #[test]
     fn current_performs_request_to_api() {
@@ -105,12 +197,61 @@ mod tests {
             .create();
 
         let mut core = tokio_core::reactor::Core::new().unwrap();
-        let handle = core.handle();
-        let work = current(&handle, "Tomsk", "");
+        let client = HttpClient::new(&core.handle());
+        let work = current(&client, &Location::Name("Tomsk".to_owned()), &key(""));
         let r = core.run(work);
 
-        assert_eq!(r.unwrap(), Ok(-14.0));
+        assert_eq!(r.unwrap(), Ok(CurrentObservation {
+            temperature: -14.0,
+            humidity: Some(78.0),
+            pressure: Some(1032.0),
+            wind_speed: Some(0.0),
+            wind_direction: Some(0.0),
+            cloud_cover: Some(0.0),
+            visibility: Some(5.0),
+            precipitation: Some(0.0),
+            condition: Some("Overcast".to_owned()),
+            observed_at: Some(1517661028),
+            coordinates: Some(Coordinates::new(56.5, 84.97))
+        }));
+        m1.assert();
+    }
+
+    #[test]
+    fn current_queries_by_coordinates_and_ip() {
+        let m1 = mock("GET", Matcher::Regex(r#"^/current.json\?key=.*&q=56.5,84.97$"#.to_string()))
+            .with_status(400)
+            .with_body(r#"{"error":{"code":1006,"message":"No matching location found."}}"#)
+            .create();
+        let m2 = mock("GET", Matcher::Regex(r#"^/current.json\?key=.*&q=93.184.216.34$"#.to_string()))
+            .with_status(400)
+            .with_body(r#"{"error":{"code":1006,"message":"No matching location found."}}"#)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let by_coordinates = current(&client, &Location::Coordinates { lat: 56.5, lon: 84.97 }, &key(""));
+        let by_ip = current(&client, &Location::Ip("93.184.216.34".parse().unwrap()), &key(""));
+        let r = core.run(by_coordinates.join(by_ip));
+
+        assert_eq!(r.unwrap(), (Err(ApiError::LocationNotFound), Err(ApiError::LocationNotFound)));
         m1.assert();
+        m2.assert();
+    }
+
+    #[test]
+    fn current_queries_postal_codes_with_country() {
+        let m = mock("GET", Matcher::Regex(r#"^/current.json\?key=.*&q=94040,us$"#.to_string()))
+            .with_status(400)
+            .with_body(r#"{"error":{"code":1006,"message":"No matching location found."}}"#)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let work = current(&client, &Location::PostalCode { code: "94040".to_owned(), country: Some("us".to_owned()) }, &key(""));
+
+        assert_eq!(core.run(work).unwrap(), Err(ApiError::LocationNotFound));
+        m.assert();
     }
 
     #[test]
@@ -122,14 +263,54 @@ mod tests {
             .create();
 
         let mut core = tokio_core::reactor::Core::new().unwrap();
-        let handle = core.handle();
-        let work = current(&handle, "new-ork", "");
+        let client = HttpClient::new(&core.handle());
+        let work = current(&client, &Location::Name("new-ork".to_owned()), &key(""));
         let r = core.run(work);
 
         assert_eq!(r.unwrap(), Err(ApiError::LocationNotFound));
         m2.assert();
     }
 
+    #[test]
+    fn resolve_reports_canonical_location() {
+        let m = mock("GET", Matcher::Regex(r#"^/timezone.json\?key=.*&q=perm$"#.to_string()))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"location":{"name":"Perm","region":"Perm'","country":"Russia","lat":58.0,"lon":56.25,"tz_id":"Asia/Yekaterinburg","localtime_epoch":1517670734,"localtime":"2018-02-03 20:12"}}"#)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let work = resolve(&client, &Location::Name("perm".to_owned()), &key(""));
+        let r = core.run(work);
+
+        assert_eq!(r.unwrap(), Ok(CanonicalLocation {
+            name: "Perm".to_owned(),
+            region: Some("Perm'".to_owned()),
+            country: Some("Russia".to_owned()),
+            coordinates: Coordinates::new(58.0, 56.25),
+            timezone: Some("Asia/Yekaterinburg".to_owned())
+        }));
+        m.assert();
+    }
+
+    #[test]
+    fn resolve_handles_wrong_cities() {
+        let m = mock("GET", Matcher::Regex(r#"^/timezone.json.*new-ork.*"#.to_string()))
+            .with_status(400)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"error":{"code":1006,"message":"No matching location found."}}"#)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let work = resolve(&client, &Location::Name("new-ork".to_owned()), &key(""));
+        let r = core.run(work);
+
+        assert_eq!(r.unwrap(), Err(ApiError::LocationNotFound));
+        m.assert();
+    }
+
     #[test]
     fn forecast_handles_proper_response() {
         let m3 = mock("GET", Matcher::Regex(r#"^/forecast.json.*Perm.*"#.to_string()))
@@ -139,11 +320,32 @@ mod tests {
             .create();
 
         let mut core = tokio_core::reactor::Core::new().unwrap();
-        let handle = core.handle();
-        let work = forecast(&handle, "Perm", "");
+        let client = HttpClient::new(&core.handle());
+        let work = forecast(&client, &Location::Name("Perm".to_owned()), &key(""));
         let r = core.run(work);
 
-        assert_eq!(r.unwrap(), Ok(vec![Some(-9.8), Some(-6.8), Some(-4.2), Some(-5.8), Some(-7.6)]));
+        let forecast = r.unwrap().unwrap();
+        assert_eq!(forecast.timezone, Some("Asia/Yekaterinburg".to_owned()));
+        assert_eq!(forecast.utc_offset, Some(5 * 3600));
+        assert_eq!(forecast.coordinates, Some(Coordinates::new(58.0, 56.25)));
+
+        let temps: Vec<(String, Option<f32>)> = forecast.days.iter().map(|(date, d)| (date.to_string(), d.temperature)).collect();
+        assert_eq!(temps, vec![
+            ("2018-02-03".to_owned(), Some(-9.8)),
+            ("2018-02-04".to_owned(), Some(-6.8)),
+            ("2018-02-05".to_owned(), Some(-4.2)),
+            ("2018-02-06".to_owned(), Some(-5.8)),
+            ("2018-02-07".to_owned(), Some(-7.6))
+        ]);
+        assert_eq!(forecast.days[&ForecastDate::new(2018, 2, 3)], DailyForecast {
+            temperature: Some(-9.8),
+            min_temperature: Some(-13.0),
+            max_temperature: Some(-5.7),
+            precipitation: Some(0.2),
+            precipitation_probability: None,
+            wind_speed: Some(14.4 / 3.6),
+            condition: Some("Light freezing rain".to_owned())
+        });
         m3.assert();
     }
 
@@ -156,8 +358,8 @@ mod tests {
             .create();
 
         let mut core = tokio_core::reactor::Core::new().unwrap();
-        let handle = core.handle();
-        let work = forecast(&handle, "new-ork", "");
+        let client = HttpClient::new(&core.handle());
+        let work = forecast(&client, &Location::Name("new-ork".to_owned()), &key(""));
         let r = core.run(work);
 
         assert_eq!(r.unwrap(), Err(ApiError::LocationNotFound));
@@ -165,7 +367,7 @@ mod tests {
     }
 
     #[test]
-    fn forecast_fills_values_upto_five_with_none() {
+    fn forecast_keeps_only_reported_days() {
         let m = mock("GET", Matcher::Regex(r#"^/forecast.json.*Perm.*"#.to_string()))
             .with_status(200)
             .with_header("content-type", "application/json")
@@ -173,11 +375,37 @@ mod tests {
             .create();
 
         let mut core = tokio_core::reactor::Core::new().unwrap();
-        let handle = core.handle();
-        let work = forecast(&handle, "Perm", "");
+        let client = HttpClient::new(&core.handle());
+        let work = forecast(&client, &Location::Name("Perm".to_owned()), &key(""));
         let r = core.run(work);
 
-        assert_eq!(r.unwrap(), Ok(vec![Some(-9.8), Some(-6.8), Some(-4.2), None, None]));
+        let forecast = r.unwrap().unwrap();
+        let temps: Vec<(String, Option<f32>)> = forecast.days.iter().map(|(date, d)| (date.to_string(), d.temperature)).collect();
+        assert_eq!(temps, vec![
+            ("2018-02-03".to_owned(), Some(-9.8)),
+            ("2018-02-04".to_owned(), Some(-6.8)),
+            ("2018-02-05".to_owned(), Some(-4.2))
+        ]);
         m.assert();
     }
+
+    #[test]
+    fn it_maps_error_codes() {
+        let m1 = mock("GET", Matcher::Regex(r#"^/current.json\?key=used-up.*"#.to_string()))
+            .with_status(403)
+            .with_body(r#"{"error":{"code":2007,"message":"API key has exceeded calls per month quota."}}"#)
+            .create();
+        let m2 = mock("GET", Matcher::Regex(r#"^/current.json\?key=revoked.*"#.to_string()))
+            .with_status(401)
+            .with_body(r#"{"error":{"code":2006,"message":"API key provided is invalid"}}"#)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let tomsk = Location::Name("tomsk".to_owned());
+        assert_eq!(core.run(current(&client, &tomsk, &key("used-up"))).unwrap(), Err(ApiError::QuotaExceeded));
+        assert_eq!(core.run(current(&client, &tomsk, &key("revoked"))).unwrap(), Err(ApiError::InvalidApiKey));
+        m1.assert();
+        m2.assert();
+    }
 }
@@ -0,0 +1,328 @@
+extern crate futures;
+extern crate hyper;
+extern crate hyper_tls;
+extern crate native_tls;
+extern crate tokio_core;
+
+use std::io;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use self::futures::Future;
+use self::futures::unsync::oneshot;
+use self::hyper::{Client, Uri};
+use self::hyper::client::{FutureResponse, HttpConnector};
+use self::hyper_tls::HttpsConnector;
+use self::native_tls::{Certificate, TlsConnector};
+use self::tokio_core::reactor::Handle;
+
+use async_request::ApiFuture;
+use async_request::retry::{RetryConfig, RetryPolicy};
+
+// Threads resolving provider hostnames
+const DNS_THREADS: usize = 4;
+
+// Idle connections are closed after `idle_timeout`,
+// at most `max_per_host` requests run against a host at a time.
+// Certificates of `ca_bundle` are trusted in addition to the system ones
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientConfig {
+    pub idle_timeout: Duration,
+    pub max_per_host: usize,
+    pub ca_bundle: Option<PathBuf>,
+    pub retries: RetryConfig
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            idle_timeout: Duration::from_secs(90),
+            max_per_host: 8,
+            ca_bundle: None,
+            retries: RetryConfig::default()
+        }
+    }
+}
+
+// Asked before every upstream attempt, retries included. The attempt is made
+// once it resolves to Ok, a refused first attempt fails with its error
+pub type Meter = Rc<Fn() -> ApiFuture<()>>;
+
+// Long-lived client shared by every provider, speaks both http and https
+// and keeps connections alive between requests. Clones share the connection pool and limits.
+// Requests are retried by the default policy unless the client is scoped to a provider
+#[derive(Clone)]
+pub struct HttpClient {
+    client: Client<HttpsConnector<HttpConnector>>,
+    handle: Handle,
+    limits: HostLimits,
+    retries: Rc<RetryConfig>,
+    policy: RetryPolicy,
+    deadline: Option<Instant>,
+    meter: Option<Meter>
+}
+
+impl HttpClient {
+    // Client with default settings, panics when TLS isn't available
+    pub fn new(handle: &Handle) -> HttpClient {
+        HttpClient::with_config(handle, &ClientConfig::default()).unwrap()
+    }
+
+    pub fn with_config(handle: &Handle, config: &ClientConfig) -> Result<HttpClient, String> {
+        let mut http = HttpConnector::new(DNS_THREADS, handle);
+        http.enforce_http(false);
+        let tls = tls_connector(config.ca_bundle.as_ref().map(|p| p.as_path()))?;
+
+        let client = Client::configure()
+            .connector(HttpsConnector::from((http, tls)))
+            .keep_alive(true)
+            .keep_alive_timeout(Some(config.idle_timeout))
+            .build(handle);
+
+        Ok(HttpClient {
+            client: client,
+            handle: handle.clone(),
+            limits: HostLimits::new(config.max_per_host),
+            retries: Rc::new(config.retries.clone()),
+            policy: config.retries.default,
+            deadline: None,
+            meter: None
+        })
+    }
+
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    // Client retrying requests by the provider's policy
+    pub fn for_provider(&self, provider: &str) -> HttpClient {
+        HttpClient { policy: self.retries.policy(provider), ..self.clone() }
+    }
+
+    // Client which doesn't start retries past `deadline`
+    pub fn with_deadline(&self, deadline: Option<Instant>) -> HttpClient {
+        HttpClient { deadline: deadline, ..self.clone() }
+    }
+
+    // Client making its attempts only once `meter` allows them
+    pub fn with_meter(&self, meter: Meter) -> HttpClient {
+        HttpClient { meter: Some(meter), ..self.clone() }
+    }
+
+    // Whether the next attempt may be made, always allowed without a meter
+    pub fn allowance(&self) -> ApiFuture<()> {
+        match self.meter {
+            Some(ref meter) => meter(),
+            None => Box::new(futures::future::ok(Ok(())))
+        }
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.policy
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    // Waits for a free slot of the host, the slot is taken until `Permit` is dropped
+    pub fn permit(&self, uri: &Uri) -> Box<Future<Item = Permit, Error = hyper::Error>> {
+        let host = format!("{}:{}", uri.host().unwrap_or(""), uri.port().unwrap_or(0));
+        self.limits.acquire(host)
+    }
+
+    pub fn get(&self, uri: Uri) -> FutureResponse {
+        self.client.get(uri)
+    }
+}
+
+fn tls_connector(ca_bundle: Option<&Path>) -> Result<TlsConnector, String> {
+    let mut builder = TlsConnector::builder().map_err(|e| format!("Failed to set up TLS: {}", e))?;
+
+    if let Some(path) = ca_bundle {
+        let mut pem = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut pem))
+            .map_err(|e| format!("Failed to read CA bundle {}: {}", path.display(), e))?;
+
+        for certificate in pem_certificates(&pem) {
+            let certificate = Certificate::from_pem(certificate.as_bytes())
+                .map_err(|e| format!("Invalid certificate in {}: {}", path.display(), e))?;
+            builder.add_root_certificate(certificate)
+                .map_err(|e| format!("Failed to trust certificate from {}: {}", path.display(), e))?;
+        }
+    }
+
+    builder.build().map_err(|e| format!("Failed to set up TLS: {}", e))
+}
+
+// Splits a bundle into separate certificates, text around them is skipped
+fn pem_certificates(bundle: &str) -> Vec<String> {
+    const BEGIN: &'static str = "-----BEGIN CERTIFICATE-----";
+    const END: &'static str = "-----END CERTIFICATE-----";
+
+    let mut certificates = vec![];
+    let mut rest = bundle;
+    while let Some(start) = rest.find(BEGIN) {
+        match rest[start..].find(END) {
+            Some(length) => {
+                let end = start + length + END.len();
+                certificates.push(rest[start..end].to_owned());
+                rest = &rest[end..];
+            },
+            None => break
+        }
+    }
+    certificates
+}
+
+#[derive(Clone)]
+struct HostLimits {
+    max: usize,
+    hosts: Rc<RefCell<HashMap<String, HostSlots>>>
+}
+
+#[derive(Default)]
+struct HostSlots {
+    active: usize,
+    waiting: VecDeque<oneshot::Sender<Permit>>
+}
+
+// Slot of a host taken by a request, passed over to the next waiting request when dropped
+pub struct Permit {
+    host: String,
+    limits: HostLimits
+}
+
+impl HostLimits {
+    fn new(max: usize) -> HostLimits {
+        HostLimits { max: max.max(1), hosts: Rc::new(RefCell::new(HashMap::new())) }
+    }
+
+    fn acquire(&self, host: String) -> Box<Future<Item = Permit, Error = hyper::Error>> {
+        let mut hosts = self.hosts.borrow_mut();
+        let slots = hosts.entry(host.clone()).or_insert_with(HostSlots::default);
+
+        if slots.active < self.max {
+            slots.active += 1;
+            return Box::new(futures::future::ok(Permit { host: host, limits: self.clone() }));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        slots.waiting.push_back(tx);
+        Box::new(rx.map_err(|_| hyper::Error::Io(io::Error::new(io::ErrorKind::Other, "host limits were dropped"))))
+    }
+
+    // Next request still waiting for the host, the slot is freed when there is none
+    fn next_waiting(&self, host: &str) -> Option<oneshot::Sender<Permit>> {
+        let mut hosts = self.hosts.borrow_mut();
+        let free = {
+            let slots = hosts.get_mut(host)?;
+            while let Some(tx) = slots.waiting.pop_front() {
+                if !tx.is_canceled() {
+                    return Some(tx);
+                }
+            }
+            slots.active -= 1;
+            slots.active == 0
+        };
+
+        if free {
+            hosts.remove(host);
+        }
+        None
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(tx) = self.limits.next_waiting(&self.host) {
+            // a permit the receiver no longer waits for is dropped and passed further
+            let _ = tx.send(Permit { host: self.host.clone(), limits: self.limits.clone() });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::futures::Async;
+
+    fn ready(permit: &mut Box<Future<Item = Permit, Error = hyper::Error>>) -> Option<Permit> {
+        match permit.poll().unwrap() {
+            Async::Ready(permit) => Some(permit),
+            Async::NotReady => None
+        }
+    }
+
+    #[test]
+    fn it_limits_concurrent_requests_per_host() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::with_config(&core.handle(), &ClientConfig { max_per_host: 1, ..ClientConfig::default() }).unwrap();
+        let owm: Uri = "http://api.openweathermap.org/data/2.5/weather".parse().unwrap();
+        let apixu: Uri = "http://api.apixu.com/v1/current.json".parse().unwrap();
+
+        core.run(futures::future::lazy(|| {
+            let mut first = client.permit(&owm);
+            let mut second = client.permit(&owm);
+            let mut other_host = client.permit(&apixu);
+
+            let first = ready(&mut first).expect("first request should get a slot");
+            assert!(ready(&mut second).is_none());
+            assert!(ready(&mut other_host).is_some());
+
+            drop(first);
+            assert!(ready(&mut second).is_some());
+            Ok::<(), ()>(())
+        })).unwrap();
+    }
+
+    #[test]
+    fn it_splits_ca_bundles() {
+        let bundle = "# local stand-in\n-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n\
+                      -----BEGIN CERTIFICATE-----\nMIIC\n-----END CERTIFICATE-----\n-----BEGIN CERTIFICATE-----\ntruncated";
+
+        assert_eq!(pem_certificates(bundle), vec![
+            "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----".to_owned(),
+            "-----BEGIN CERTIFICATE-----\nMIIC\n-----END CERTIFICATE-----".to_owned()
+        ]);
+        assert_eq!(pem_certificates(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn it_reports_unreadable_ca_bundle() {
+        let core = tokio_core::reactor::Core::new().unwrap();
+        let config = ClientConfig { ca_bundle: Some(PathBuf::from("/nonexistent/ca.pem")), ..ClientConfig::default() };
+
+        match HttpClient::with_config(&core.handle(), &config) {
+            Err(e) => assert!(e.starts_with("Failed to read CA bundle /nonexistent/ca.pem")),
+            Ok(_) => panic!("missing CA bundle should be reported")
+        }
+    }
+
+    #[test]
+    fn abandoned_waiters_dont_hold_slots() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::with_config(&core.handle(), &ClientConfig { max_per_host: 1, ..ClientConfig::default() }).unwrap();
+        let owm: Uri = "http://api.openweathermap.org/data/2.5/weather".parse().unwrap();
+
+        core.run(futures::future::lazy(|| {
+            let first = ready(&mut client.permit(&owm)).unwrap();
+            let abandoned = client.permit(&owm);
+            let mut waiting = client.permit(&owm);
+            assert!(ready(&mut waiting).is_none());
+
+            drop(abandoned);
+            drop(first);
+            let permit = ready(&mut waiting).expect("waiting request should get the slot");
+
+            drop(permit);
+            assert!(client.limits.hosts.borrow().is_empty());
+            Ok::<(), ()>(())
+        })).unwrap();
+    }
+}
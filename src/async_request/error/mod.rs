@@ -1,20 +1,63 @@
+extern crate hyper;
+
 use std;
 use std::fmt;
 use std::error::Error;
+use std::time::Duration;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ApiError {
     LocationNotFound,
+    // provider reported weather for a place far from the requested one
+    LocationMismatch,
     Timeout,
+    // connection to the provider failed or was dropped
+    Connect,
+    InvalidApiKey,
+    // delay the provider asked to wait for, if it told
+    RateLimited(Option<Duration>),
+    // daily or monthly calls of the key are used up
+    QuotaExceeded,
+    // calls of the day our own budget of the key allows are used up
+    BudgetExhausted,
+    UpstreamServerError(u16),
+    // body isn't what the provider is expected to answer with
+    MalformedResponse(String),
+    // provider didn't answer before the response was built
+    Skipped,
+    // provider kept failing and isn't called for a while
+    CircuitOpen,
     Other
 }
 
+impl ApiError {
+    // Status the server answers with when every provider failed this way
+    pub fn http_status(&self) -> hyper::StatusCode {
+        match *self {
+            ApiError::LocationNotFound | ApiError::LocationMismatch => hyper::StatusCode::NotFound,
+            ApiError::Timeout | ApiError::Skipped => hyper::StatusCode::GatewayTimeout,
+            ApiError::RateLimited(_) | ApiError::QuotaExceeded | ApiError::BudgetExhausted | ApiError::CircuitOpen => hyper::StatusCode::ServiceUnavailable,
+            _ => hyper::StatusCode::BadGateway
+        }
+    }
+}
 
 impl fmt::Display for ApiError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             ApiError::LocationNotFound => f.write_str("LocationNotFound"),
+            ApiError::LocationMismatch => f.write_str("LocationMismatch"),
             ApiError::Timeout => f.write_str("Timeout"),
+            ApiError::Connect => f.write_str("Connect"),
+            ApiError::InvalidApiKey => f.write_str("InvalidApiKey"),
+            ApiError::RateLimited(Some(delay)) => write!(f, "RateLimited (retry after {}s)", delay.as_secs()),
+            ApiError::RateLimited(None) => f.write_str("RateLimited"),
+            ApiError::QuotaExceeded => f.write_str("QuotaExceeded"),
+            ApiError::BudgetExhausted => f.write_str("BudgetExhausted"),
+            ApiError::UpstreamServerError(status) => write!(f, "UpstreamServerError ({})", status),
+            ApiError::MalformedResponse(ref detail) => write!(f, "MalformedResponse ({})", detail),
+            ApiError::Skipped => f.write_str("Skipped"),
+            ApiError::CircuitOpen => f.write_str("CircuitOpen"),
             ApiError::Other => f.write_str("Other"),
         }
     }
@@ -24,14 +67,45 @@ impl Error for ApiError {
     fn description(&self) -> &str {
         match *self {
             ApiError::LocationNotFound => "Location wasnt found",
+            ApiError::LocationMismatch => "Reported location is too far from the requested one",
             ApiError::Timeout => "Connection timed out",
+            ApiError::Connect => "Connection to provider failed",
+            ApiError::InvalidApiKey => "Provider rejected the api key",
+            ApiError::RateLimited(_) => "Provider limits the rate of requests",
+            ApiError::QuotaExceeded => "Api key quota is exceeded",
+            ApiError::BudgetExhausted => "Daily budget of the api key is used up",
+            ApiError::UpstreamServerError(_) => "Provider failed to respond",
+            ApiError::MalformedResponse(_) => "Provider response is malformed",
+            ApiError::Skipped => "Provider was too late to be included",
+            ApiError::CircuitOpen => "Provider is failing and temporarily not called",
             ApiError::Other => "Other error",
         }
     }
 }
 
 impl From<std::io::Error> for ApiError {
-    fn from(_err: std::io::Error) -> Self {
-        ApiError::Other
+    fn from(err: std::io::Error) -> Self {
+        // requests the client's meter refused carry the reason
+        if let Some(e) = err.get_ref().and_then(|e| e.downcast_ref::<ApiError>()) {
+            return e.clone();
+        }
+
+        match err.kind() {
+            std::io::ErrorKind::TimedOut => ApiError::Timeout,
+            _ => ApiError::Connect
+        }
+    }
+}
+
+// Transport errors, responses the provider did send are mapped by `async_api_request`
+impl From<hyper::Error> for ApiError {
+    fn from(err: hyper::Error) -> Self {
+        match err {
+            hyper::Error::Io(io) => ApiError::from(io),
+            hyper::Error::Timeout => ApiError::Timeout,
+            hyper::Error::Incomplete | hyper::Error::Closed | hyper::Error::Cancel(_) => ApiError::Connect,
+            hyper::Error::Uri(_) => ApiError::Other,
+            other => ApiError::MalformedResponse(other.to_string())
+        }
     }
 }
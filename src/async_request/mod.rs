@@ -5,52 +5,129 @@ extern crate serde_json;
 
 use std;
 use std::io;
-use std::time::Duration;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant, SystemTime};
 
-use self::hyper::{Client};
 use self::serde_json::Value;
 use self::futures::{Future, Stream};
-use self::futures::future::Either;
-use self::tokio_core::reactor::{Timeout, Handle};
+use self::futures::future::{Either, Shared};
+use self::tokio_core::reactor::Timeout;
 
 #[cfg(not(test))]
 static TIMEOUT: u64 = 5;
 
 pub mod error;
+pub mod client;
+pub mod retry;
+
+use self::error::ApiError;
+use self::client::HttpClient;
+use self::retry::Failure;
+use keys::KeyRing;
 
 #[derive(Debug)]
 pub struct AsyncResponse {
     pub status: hyper::StatusCode,
+    // delay the server asked to wait for before the next request
+    pub retry_after: Option<Duration>,
     pub body: hyper::Chunk
 }
 
 #[derive(Debug, Clone)]
 pub struct AsyncJsonResponse {
     pub status: hyper::StatusCode,
+    pub retry_after: Option<Duration>,
     pub body: Option<Value>
 }
 
 pub type Result<T> = std::result::Result<T, self::error::ApiError>;
 
-pub fn async_request(handle: &Handle, url: &str) -> Box<Future<Item = AsyncResponse, Error = hyper::Error>> {
-    let timeout = Timeout::new(Duration::from_secs(TIMEOUT), &handle).unwrap();
-    let client = Client::configure().build(&handle);
-    let uri = url.parse().unwrap();
+pub type ApiFuture<T> = Box<Future<Item = Result<T>, Error = hyper::Error>>;
+
+// Failed attempts are repeated according to the client's retry policy
+// as long as the next one starts before the client's deadline. Every attempt
+// waits for the client's meter: a refused request fails with the meter's error,
+// refused retries are given up
+pub fn async_request(client: &HttpClient, url: &str) -> Box<Future<Item = AsyncResponse, Error = hyper::Error>> {
+    let uri: hyper::Uri = match url.parse() {
+        Ok(uri) => uri,
+        Err(e) => return Box::new(futures::future::err(hyper::Error::Uri(e)))
+    };
+
+    let client = client.clone();
+    Box::new(client.allowance().and_then(move |allowed| -> ResponseFuture {
+        match allowed {
+            Ok(()) => with_retries(client, uri, 1),
+            Err(e) => Box::new(futures::future::err(refused(e)))
+        }
+    }))
+}
+
+type ResponseFuture = Box<Future<Item = AsyncResponse, Error = hyper::Error>>;
+
+// Request the meter refused, the reason is carried in the io error
+// for `ApiError::from` to give it back
+fn refused(e: ApiError) -> hyper::Error {
+    hyper::Error::Io(io::Error::new(io::ErrorKind::Other, e))
+}
+
+fn with_retries(client: HttpClient, uri: hyper::Uri, attempt: u32) -> ResponseFuture {
+    Box::new(single_attempt(&client, uri.clone()).then(move |result| -> ResponseFuture {
+        let failure = match result {
+            Ok(ref resp) => Failure::of_status(resp.status, resp.retry_after),
+            Err(ref e) => Some(Failure::of_error(e))
+        };
+        let delay = failure.and_then(|f| client.retry_policy().next_delay(attempt, &f))
+            .filter(|delay| client.deadline().map(|d| Instant::now() + *delay < d).unwrap_or(true));
+
+        match delay {
+            Some(delay) => {
+                let wait = match Timeout::new(delay, client.handle()) {
+                    Ok(wait) => wait,
+                    Err(_) => return Box::new(futures::future::result(result))
+                };
+                let metered = client.clone();
+                Box::new(wait.map_err(hyper::Error::from).and_then(move |_| metered.allowance()).and_then(move |allowed| -> ResponseFuture {
+                    match allowed {
+                        Ok(()) => with_retries(client, uri, attempt + 1),
+                        Err(_) => Box::new(futures::future::result(result))
+                    }
+                }))
+            },
+            None => Box::new(futures::future::result(result))
+        }
+    }))
+}
 
-    let resp = client.get(uri).and_then(|web_res| {
+// Waiting for a free connection slot of the host counts towards the timeout,
+// the slot is held until the whole body is read
+fn single_attempt(client: &HttpClient, uri: hyper::Uri) -> ResponseFuture {
+    let timeout = Timeout::new(Duration::from_secs(TIMEOUT), client.handle()).unwrap();
+    let host = uri.host().unwrap_or("").to_owned();
+
+    let requests = client.clone();
+    let resp = client.permit(&uri).and_then(move |permit| requests.get(uri).and_then(move |web_res| {
         let status = web_res.status();
-        web_res.body().concat2().map(move |body|
+        let retry_after = web_res.headers().get::<hyper::header::RetryAfter>().map(|r| match *r {
+            hyper::header::RetryAfter::Delay(delay) => delay,
+            hyper::header::RetryAfter::DateTime(date) => SystemTime::from(date).duration_since(SystemTime::now()).unwrap_or(Duration::from_secs(0))
+        });
+        web_res.body().concat2().map(move |body| {
+            drop(permit);
             AsyncResponse {
                 status: status,
+                retry_after: retry_after,
                 body: body
             }
-        )
-    }).select2(timeout).then(|res| match res {
+        })
+    })).select2(timeout).then(move |res| match res {
         Ok(Either::A((got, _timeout))) => Ok(got),
         Ok(Either::B((_timeout_error, _get))) => {
             Err(hyper::Error::Io(io::Error::new(
                 io::ErrorKind::TimedOut,
-                "timed out while connecting to owm",
+                format!("timed out waiting for {}", host),
             )))
         }
         Err(Either::A((get_error, _timeout))) => Err(get_error),
@@ -60,17 +137,131 @@ pub fn async_request(handle: &Handle, url: &str) -> Box<Future<Item = AsyncRespo
     Box::new(resp)
 }
 
-pub fn async_json_request(handle: &Handle, url: &str) -> Box<Future<Item = AsyncJsonResponse, Error = hyper::Error>> {
-    let resp = async_request(handle, url).and_then(|async_resp| {
+pub fn async_json_request(client: &HttpClient, url: &str) -> Box<Future<Item = AsyncJsonResponse, Error = hyper::Error>> {
+    let resp = async_request(client, url).and_then(|async_resp| {
         let v: serde_json::Result<Value> = serde_json::from_slice(&async_resp.body);
-        Ok(match v {
-            Ok(parsed_body) => AsyncJsonResponse { status: async_resp.status, body: Some(parsed_body) },
-            Err(_) => AsyncJsonResponse { status: async_resp.status, body: None },
-        })
+        Ok(AsyncJsonResponse { status: async_resp.status, retry_after: async_resp.retry_after, body: v.ok() })
     });
     Box::new(resp)
 }
 
+type InFlight = Shared<ApiFuture<Value>>;
+
+thread_local! {
+    // api requests being performed on this thread's reactor, keyed by
+    // their urls without the api key, along with those waiting for them
+    static IN_FLIGHT: RefCell<HashMap<String, (InFlight, Weak<Waiters>)>> = RefCell::new(HashMap::new());
+}
+
+// Shared by everyone waiting for an in-flight request. Once the last of them
+// is gone nobody polls the request anymore, so it is forgotten and dropped
+struct Waiters {
+    key: String
+}
+
+impl Drop for Waiters {
+    fn drop(&mut self) {
+        let _ = IN_FLIGHT.try_with(|in_flight| {
+            let mut in_flight = in_flight.borrow_mut();
+            let abandoned = in_flight.get(&self.key).map(|&(_, ref waiters)| waiters.upgrade().is_none()).unwrap_or(false);
+            if abandoned {
+                in_flight.remove(&self.key);
+            }
+        });
+    }
+}
+
+// Request sharing its result with requests of the same `identity` made while it is in flight,
+// only the first of them is made
+fn coalesced<F>(identity: &str, request: F) -> ApiFuture<Value>
+    where F: FnOnce() -> ApiFuture<Value> {
+    let (request, waiters) = IN_FLIGHT.with(|in_flight| {
+        let mut in_flight = in_flight.borrow_mut();
+        if let Some(&(ref request, ref waiters)) = in_flight.get(identity) {
+            if let Some(waiters) = waiters.upgrade() {
+                return (request.clone(), waiters);
+            }
+        }
+
+        let key = identity.to_owned();
+        let request: ApiFuture<Value> = Box::new(request().then(move |result| {
+            IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&key));
+            result
+        }));
+        let request = request.shared();
+        let waiters = Rc::new(Waiters { key: identity.to_owned() });
+        in_flight.insert(identity.to_owned(), (request.clone(), Rc::downgrade(&waiters)));
+        (request, waiters)
+    });
+
+    Box::new(request.then(move |result| {
+        drop(waiters);
+        match result {
+            Ok(response) => Ok((*response).clone()),
+            Err(e) => Err(unshare_error(&e))
+        }
+    }))
+}
+
+// Shared errors can't be moved out to every waiter, so they are rebuilt keeping their kind
+fn unshare_error(e: &hyper::Error) -> hyper::Error {
+    match *e {
+        hyper::Error::Io(ref io) => match io.get_ref().and_then(|e| e.downcast_ref::<ApiError>()) {
+            Some(refusal) => refused(refusal.clone()),
+            None => hyper::Error::Io(io::Error::new(io.kind(), io.to_string()))
+        },
+        hyper::Error::Timeout => hyper::Error::Timeout,
+        hyper::Error::Incomplete => hyper::Error::Incomplete,
+        hyper::Error::Closed => hyper::Error::Closed,
+        ref other => hyper::Error::Io(io::Error::new(io::ErrorKind::Other, other.to_string()))
+    }
+}
+
+// Errors every provider answers with the same statuses,
+// None when the response isn't one of them
+pub fn status_error(response: &AsyncJsonResponse) -> Option<ApiError> {
+    match response.status {
+        hyper::StatusCode::Unauthorized => Some(ApiError::InvalidApiKey),
+        hyper::StatusCode::TooManyRequests => Some(ApiError::RateLimited(response.retry_after)),
+        status if status.is_server_error() => Some(ApiError::UpstreamServerError(status.as_u16())),
+        status if status.is_client_error() => Some(ApiError::Other),
+        _ => None
+    }
+}
+
+// Performs a json request to a provider api with the url `url` gives for a key of `keys`
+// and maps the response into `Result<T>`: `errors` picks out error responses of the provider,
+// other responses without a body `parse` accepts are `ApiError::MalformedResponse`, transport
+// errors are `ApiError::Timeout` or `ApiError::Connect`. Concurrent requests for the same url
+// are coalesced into one whichever keys they would be made with
+pub fn async_api_request<T, U, F>(client: &HttpClient, keys: &KeyRing, url: U, errors: fn(&AsyncJsonResponse) -> Option<ApiError>, parse: F) -> ApiFuture<T>
+    where T: 'static, U: Fn(&str) -> String + 'static, F: FnOnce(Value) -> serde_json::Result<T> + 'static {
+    let identity = url("");
+    let (client, keys) = (client.clone(), keys.clone());
+    let body = coalesced(&identity, move || keys.call(&client, move |client, key| json_api_request(client, &url(key), errors)));
+
+    Box::new(body.map(move |result| result.and_then(|body| parse(body).map_err(|e| ApiError::MalformedResponse(e.to_string())))))
+}
+
+// Body of a successful json response of a provider api
+fn json_api_request(client: &HttpClient, url: &str, errors: fn(&AsyncJsonResponse) -> Option<ApiError>) -> ApiFuture<Value> {
+    Box::new(async_json_request(client, url).then(move |result| {
+        let s = match result {
+            Ok(s) => s,
+            Err(e) => return Ok(Err(ApiError::from(e)))
+        };
+
+        if let Some(e) = errors(&s).or_else(|| status_error(&s)) {
+            return Ok(Err(e));
+        }
+
+        match s.body {
+            Some(body) => Ok(Ok(body)),
+            None => Ok(Err(ApiError::MalformedResponse(format!("{} response isn't json", s.status.as_u16()))))
+        }
+    }))
+}
+
 #[cfg(test)]
 extern crate mockito;
 
@@ -84,6 +275,7 @@ static TIMEOUT: u64 = 10;
 mod tests {
     use super::*;
     use self::mockito::{mock};
+    use keys::{KeysConfig, parse_keys};
 
     #[test]
     fn it_performs_request() {
@@ -93,8 +285,8 @@ mod tests {
             .create();
 
         let mut core = tokio_core::reactor::Core::new().unwrap();
-        let handle = core.handle();
-        let work = async_json_request(&handle, &format!("{}/some-url", URL));
+        let client = HttpClient::new(&core.handle());
+        let work = async_json_request(&client, &format!("{}/some-url", URL));
         let r = core.run(work);
 
         assert_eq!(r.unwrap().body.unwrap(), "string-json-response");
@@ -109,11 +301,282 @@ mod tests {
             .create();
 
         let mut core = tokio_core::reactor::Core::new().unwrap();
-        let handle = core.handle();
-        let work = async_json_request(&handle, &format!("{}/some-url", URL));
+        let client = HttpClient::new(&core.handle());
+        let work = async_json_request(&client, &format!("{}/some-url", URL));
         let r = core.run(work);
 
         assert!(r.unwrap().body.is_none());
         m.assert();
     }
+
+    #[test]
+    fn it_fails_on_invalid_urls() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let work = async_json_request(&client, "http://exam ple.domain/some-url");
+        let r = core.run(work);
+
+        assert!(r.is_err());
+    }
+
+    fn keys(keys: &str) -> KeyRing {
+        KeyRing::new(&parse_keys(keys), KeysConfig::default())
+    }
+
+    fn temperature(body: Value) -> serde_json::Result<f32> {
+        serde_json::from_value::<f32>(body["temp"].clone())
+    }
+
+    #[test]
+    fn it_coalesces_concurrent_identical_requests() {
+        let m = mock("GET", "/coalesced-url")
+            .with_status(200)
+            .with_body(r#"{"temp":-14.0}"#)
+            .expect(2)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let ring = keys("key");
+        let request = || async_api_request(&client, &ring, |_| format!("{}/coalesced-url", URL), not_found, temperature);
+
+        let (a, b) = core.run(request().join(request())).unwrap();
+        assert_eq!(a, Ok(-14.0));
+        assert_eq!(b, Ok(-14.0));
+
+        // finished requests are not reused
+        assert_eq!(core.run(request()).unwrap(), Ok(-14.0));
+        m.assert();
+    }
+
+    #[test]
+    fn requests_with_different_keys_are_coalesced() {
+        let m = mock("GET", "/keyed-url/first")
+            .with_status(200)
+            .with_body(r#"{"temp":-14.0}"#)
+            .expect(1)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        // the second request would be made with the second key
+        let ring = keys("first,second");
+        let request = || async_api_request(&client, &ring, |key| format!("{}/keyed-url/{}", URL, key), not_found, temperature);
+
+        assert_eq!(core.run(request().join(request())).unwrap(), (Ok(-14.0), Ok(-14.0)));
+        m.assert();
+    }
+
+    #[test]
+    fn abandoned_requests_are_not_joined() {
+        let m = mock("GET", "/abandoned-url")
+            .with_status(200)
+            .with_body(r#"{"temp":-14.0}"#)
+            .expect(1)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let ring = keys("key");
+        let request = || async_api_request(&client, &ring, |_| format!("{}/abandoned-url", URL), not_found, temperature);
+
+        // every waiter gives up before the response arrives
+        let (first, second) = (request(), request());
+        drop(first);
+        drop(second);
+        assert!(IN_FLIGHT.with(|in_flight| in_flight.borrow().is_empty()));
+
+        assert_eq!(core.run(request()).unwrap(), Ok(-14.0));
+        m.assert();
+    }
+
+    #[test]
+    fn coalesced_requests_share_errors() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let ring = keys("key");
+        let request = || async_api_request(&client, &ring, |_| "http://exam ple.domain/coalesced-url".to_owned(), not_found, temperature);
+
+        let (a, b) = core.run(request().join(request())).unwrap();
+
+        assert!(a.is_err());
+        assert_eq!(a, b);
+    }
+
+    fn retrying_client(handle: &tokio_core::reactor::Handle) -> HttpClient {
+        let policy = retry::RetryPolicy { max_attempts: 3, backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(5), jitter: 0.0 };
+        let config = client::ClientConfig { retries: retry::RetryConfig { default: policy, providers: HashMap::new() }, ..client::ClientConfig::default() };
+        HttpClient::with_config(handle, &config).unwrap()
+    }
+
+    #[test]
+    fn it_retries_server_errors() {
+        let m = mock("GET", "/failing-url")
+            .with_status(502)
+            .expect(3)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = retrying_client(&core.handle());
+        let r = core.run(async_request(&client, &format!("{}/failing-url", URL)));
+
+        assert_eq!(r.unwrap().status, hyper::StatusCode::BadGateway);
+        m.assert();
+    }
+
+    #[test]
+    fn retries_stop_at_deadline() {
+        let m = mock("GET", "/late-url")
+            .with_status(503)
+            .expect(1)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = retrying_client(&core.handle()).with_deadline(Some(Instant::now()));
+        let r = core.run(async_request(&client, &format!("{}/late-url", URL)));
+
+        assert_eq!(r.unwrap().status, hyper::StatusCode::ServiceUnavailable);
+        m.assert();
+    }
+
+    // Meter allowing `allowed` attempts and counting all of them
+    fn counting_meter(allowed: u32) -> (client::Meter, Rc<std::cell::Cell<u32>>) {
+        let attempts = Rc::new(std::cell::Cell::new(0));
+        let counted = attempts.clone();
+        let meter: client::Meter = Rc::new(move || -> ApiFuture<()> {
+            counted.set(counted.get() + 1);
+            Box::new(futures::future::ok(if counted.get() <= allowed { Ok(()) } else { Err(ApiError::BudgetExhausted) }))
+        });
+        (meter, attempts)
+    }
+
+    #[test]
+    fn every_attempt_is_metered() {
+        let m = mock("GET", "/metered-url")
+            .with_status(502)
+            .expect(3)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let (meter, attempts) = counting_meter(10);
+        let client = retrying_client(&core.handle()).with_meter(meter);
+        let r = core.run(async_request(&client, &format!("{}/metered-url", URL)));
+
+        assert_eq!(r.unwrap().status, hyper::StatusCode::BadGateway);
+        assert_eq!(attempts.get(), 3);
+        m.assert();
+    }
+
+    #[test]
+    fn refused_retries_are_given_up() {
+        let m = mock("GET", "/refused-retry-url")
+            .with_status(503)
+            .expect(1)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let (meter, _) = counting_meter(1);
+        let client = retrying_client(&core.handle()).with_meter(meter);
+        let r = core.run(async_request(&client, &format!("{}/refused-retry-url", URL)));
+
+        assert_eq!(r.unwrap().status, hyper::StatusCode::ServiceUnavailable);
+        m.assert();
+    }
+
+    #[test]
+    fn refused_requests_fail_with_meter_error() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let (meter, _) = counting_meter(0);
+        let client = HttpClient::new(&core.handle()).with_meter(meter);
+        // nothing listens on the discard port, the request is never made
+        let work = async_api_request(&client, &keys("key"), |_| "http://127.0.0.1:9/refused-url".to_owned(), not_found, temperature);
+
+        assert_eq!(core.run(work).unwrap(), Err(ApiError::BudgetExhausted));
+    }
+
+    #[test]
+    fn only_the_sender_of_coalesced_requests_is_metered() {
+        let m = mock("GET", "/metered-coalesced-url")
+            .with_status(200)
+            .with_body(r#"{"temp":-14.0}"#)
+            .expect(1)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let (meter, attempts) = counting_meter(10);
+        let client = HttpClient::new(&core.handle()).with_meter(meter);
+
+        let ring = keys("key");
+        let request = || async_api_request(&client, &ring, |_| format!("{}/metered-coalesced-url", URL), not_found, temperature);
+        assert_eq!(core.run(request().join(request())).unwrap(), (Ok(-14.0), Ok(-14.0)));
+        assert_eq!(attempts.get(), 1);
+        m.assert();
+    }
+
+    fn not_found(response: &AsyncJsonResponse) -> Option<ApiError> {
+        if response.status == hyper::StatusCode::NotFound { Some(ApiError::LocationNotFound) } else { None }
+    }
+
+    #[test]
+    fn api_request_maps_not_found_status() {
+        let m = mock("GET", "/not-found-url")
+            .with_status(404)
+            .with_body(r#"{"message":"not found"}"#)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let work = async_api_request(&client, &keys("key"), |_| format!("{}/not-found-url", URL), not_found, temperature);
+        let r = core.run(work);
+
+        assert_eq!(r.unwrap(), Err(ApiError::LocationNotFound));
+        m.assert();
+    }
+
+    #[test]
+    fn api_request_maps_unexpected_body() {
+        let m = mock("GET", "/unexpected-body-url")
+            .with_status(200)
+            .with_body(r#"{"temp":"warm"}"#)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let work = async_api_request(&client, &keys("key"), |_| format!("{}/unexpected-body-url", URL), not_found, temperature);
+        let r = core.run(work);
+
+        assert_eq!(r.unwrap(), Err(ApiError::MalformedResponse("invalid type: string \"warm\", expected f32".to_owned())));
+        m.assert();
+    }
+
+    #[test]
+    fn api_request_maps_rate_limits() {
+        let m = mock("GET", "/limited-url")
+            .with_status(429)
+            .with_header("retry-after", "30")
+            .with_body(r#"{"message":"slow down"}"#)
+            .expect(1)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let work = async_api_request(&client, &keys("key"), |_| format!("{}/limited-url", URL), not_found, temperature);
+        let r = core.run(work);
+
+        assert_eq!(r.unwrap(), Err(ApiError::RateLimited(Some(Duration::from_secs(30)))));
+        m.assert();
+    }
+
+    #[test]
+    fn api_request_maps_transport_errors() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::with_config(&core.handle(), &client::ClientConfig {
+            retries: retry::RetryConfig { default: retry::RetryPolicy::none(), providers: HashMap::new() },
+            ..client::ClientConfig::default()
+        }).unwrap();
+        // nothing listens on the discard port
+        let work = async_api_request(&client, &keys("key"), |_| "http://127.0.0.1:9/some-url".to_owned(), not_found, temperature);
+
+        assert_eq!(core.run(work).unwrap(), Err(ApiError::Connect));
+    }
 }
@@ -0,0 +1,161 @@
+extern crate hyper;
+
+use std::io;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+// Attempt number `n` waits `backoff * 2^(n-1)` capped by `max_backoff`,
+// shortened by up to `jitter` of it at random so that retries of concurrent
+// requests spread out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    pub jitter: f32
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(2),
+            jitter: 0.5
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Requests are made once
+    pub fn none() -> RetryPolicy {
+        RetryPolicy { max_attempts: 1, ..RetryPolicy::default() }
+    }
+
+    // Delay before the attempt following `attempt`, `random` is in [0, 1)
+    pub fn backoff(&self, attempt: u32, random: f32) -> Duration {
+        let exponential = duration_ms(self.backoff).saturating_mul(1 << (attempt.max(1) - 1).min(16));
+        let capped = exponential.min(duration_ms(self.max_backoff));
+        let jitter = self.jitter.max(0.0).min(1.0);
+
+        Duration::from_millis((capped as f64 * (1.0 - (jitter * random) as f64)) as u64)
+    }
+
+    // Whether another attempt should be made and how long to wait for it.
+    // Servers asking to come back later than `max_backoff` are not retried
+    pub fn next_delay(&self, attempt: u32, failure: &Failure) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+
+        match *failure {
+            Failure::RetryAfter(delay) if delay <= self.max_backoff => Some(delay),
+            Failure::RetryAfter(_) => None,
+            Failure::Transient => Some(self.backoff(attempt, random_fraction())),
+            Failure::Permanent => None
+        }
+    }
+}
+
+// Policies of providers, the ones not listed use the default one
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RetryConfig {
+    pub default: RetryPolicy,
+    pub providers: HashMap<String, RetryPolicy>
+}
+
+impl RetryConfig {
+    pub fn policy(&self, provider: &str) -> RetryPolicy {
+        self.providers.get(provider).cloned().unwrap_or(self.default)
+    }
+}
+
+// How a failed attempt may be retried
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Failure {
+    // 429 and 503 responses telling when to come back
+    RetryAfter(Duration),
+    // connection errors and server errors, the request is safe to repeat
+    Transient,
+    Permanent
+}
+
+impl Failure {
+    pub fn of_status(status: hyper::StatusCode, retry_after: Option<Duration>) -> Option<Failure> {
+        match (status, retry_after) {
+            (hyper::StatusCode::TooManyRequests, Some(delay)) |
+            (hyper::StatusCode::ServiceUnavailable, Some(delay)) => Some(Failure::RetryAfter(delay)),
+            (hyper::StatusCode::TooManyRequests, None) => Some(Failure::Transient),
+            (status, _) if status.is_server_error() => Some(Failure::Transient),
+            _ => None
+        }
+    }
+
+    // Timed out attempts are not repeated, they've taken long enough already
+    pub fn of_error(e: &hyper::Error) -> Failure {
+        match *e {
+            hyper::Error::Io(ref io) if io.kind() == io::ErrorKind::TimedOut => Failure::Permanent,
+            hyper::Error::Io(_) | hyper::Error::Incomplete | hyper::Error::Closed | hyper::Error::Cancel(_) => Failure::Transient,
+            _ => Failure::Permanent
+        }
+    }
+}
+
+fn duration_ms(d: Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
+}
+
+// Every RandomState is seeded differently, which is random enough for jitter
+fn random_fraction() -> f32 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    (hasher.finish() % 10_000) as f32 / 10_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_limit() {
+        let policy = RetryPolicy { max_attempts: 10, backoff: Duration::from_millis(100), max_backoff: Duration::from_millis(500), jitter: 0.5 };
+
+        assert_eq!(policy.backoff(1, 0.0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2, 0.0), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3, 0.0), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4, 0.0), Duration::from_millis(500));
+        assert_eq!(policy.backoff(40, 0.0), Duration::from_millis(500));
+
+        assert_eq!(policy.backoff(2, 0.5), Duration::from_millis(150));
+        assert!(random_fraction() < 1.0);
+    }
+
+    #[test]
+    fn only_safe_failures_are_retried() {
+        let policy = RetryPolicy { max_attempts: 3, ..RetryPolicy::default() };
+        let reset = hyper::Error::Io(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+        let timed_out = hyper::Error::Io(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+
+        assert_eq!(Failure::of_error(&reset), Failure::Transient);
+        assert_eq!(Failure::of_error(&timed_out), Failure::Permanent);
+        assert_eq!(Failure::of_status(hyper::StatusCode::BadGateway, None), Some(Failure::Transient));
+        assert_eq!(Failure::of_status(hyper::StatusCode::NotFound, None), None);
+
+        assert!(policy.next_delay(1, &Failure::Transient).is_some());
+        assert_eq!(policy.next_delay(3, &Failure::Transient), None);
+        assert_eq!(policy.next_delay(1, &Failure::Permanent), None);
+    }
+
+    #[test]
+    fn retry_after_is_respected() {
+        let policy = RetryPolicy::default();
+        let soon = Failure::of_status(hyper::StatusCode::TooManyRequests, Some(Duration::from_secs(1))).unwrap();
+        let later = Failure::of_status(hyper::StatusCode::TooManyRequests, Some(Duration::from_secs(60))).unwrap();
+
+        assert_eq!(policy.next_delay(1, &soon), Some(Duration::from_secs(1)));
+        assert_eq!(policy.next_delay(1, &later), None);
+        assert_eq!(RetryPolicy::none().next_delay(1, &soon), None);
+    }
+}
@@ -0,0 +1,300 @@
+// Circuit breakers stop calling providers which keep failing, so requests
+// don't wait for them. An open breaker lets a single probe call through
+// every `open_for`, a successful probe closes it again
+
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use self::futures::Future;
+
+use async_request::ApiFuture;
+use async_request::error::ApiError;
+
+// The breaker opens once at least `min_calls` of the last `window` calls
+// were made and `failure_rate` of them failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BreakerConfig {
+    pub failure_rate: f32,
+    pub window: usize,
+    pub min_calls: usize,
+    pub open_for: Duration
+}
+
+impl Default for BreakerConfig {
+    fn default() -> BreakerConfig {
+        BreakerConfig {
+            failure_rate: 0.5,
+            window: 20,
+            min_calls: 5,
+            open_for: Duration::from_secs(30)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Closed,
+    Open,
+    // a probe call is let through to check whether the provider is back
+    HalfOpen
+}
+
+impl State {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            State::Closed => "closed",
+            State::Open => "open",
+            State::HalfOpen => "half_open"
+        }
+    }
+}
+
+// Calls made since startup
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Stats {
+    pub successes: u64,
+    pub failures: u64,
+    // calls skipped by the open breaker
+    pub rejected: u64
+}
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    state: State,
+    // outcomes of the last calls, true for failures
+    outcomes: VecDeque<bool>,
+    opened_at: Option<Instant>,
+    // when the probe still in flight was let through
+    probing: Option<Instant>,
+    stats: Stats
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            config: config,
+            state: State::Closed,
+            outcomes: VecDeque::new(),
+            opened_at: None,
+            probing: None,
+            stats: Stats::default()
+        }
+    }
+
+    pub fn state(&self, now: Instant) -> State {
+        match (self.state, self.opened_at) {
+            (State::Open, Some(at)) if now.duration_since(at) >= self.config.open_for => State::HalfOpen,
+            (state, _) => state
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    pub fn failure_rate(&self) -> f32 {
+        if self.outcomes.is_empty() {
+            return 0.0;
+        }
+        self.outcomes.iter().filter(|failed| **failed).count() as f32 / self.outcomes.len() as f32
+    }
+
+    // Whether a call may be made now. Only one probe at a time is let through
+    // a half-open breaker, unless the previous one was lost for `open_for`
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let probing = self.probing.map(|at| now.duration_since(at) < self.config.open_for).unwrap_or(false);
+        let allowed = match self.state(now) {
+            State::Closed => true,
+            State::Open => false,
+            State::HalfOpen if probing => false,
+            State::HalfOpen => {
+                self.state = State::HalfOpen;
+                self.probing = Some(now);
+                true
+            }
+        };
+
+        if !allowed {
+            self.stats.rejected += 1;
+        }
+        allowed
+    }
+
+    pub fn record(&mut self, failed: bool, now: Instant) {
+        if failed {
+            self.stats.failures += 1;
+        } else {
+            self.stats.successes += 1;
+        }
+
+        if self.state == State::HalfOpen {
+            self.probing = None;
+            if failed {
+                self.open(now);
+            } else {
+                self.state = State::Closed;
+                self.outcomes.clear();
+            }
+            return;
+        }
+
+        self.outcomes.push_back(failed);
+        while self.outcomes.len() > self.config.window {
+            self.outcomes.pop_front();
+        }
+
+        if self.state == State::Closed && self.outcomes.len() >= self.config.min_calls && self.failure_rate() >= self.config.failure_rate {
+            self.open(now);
+        }
+    }
+
+    // Call which told nothing about the provider's health, like being rate limited.
+    // A probe ending this way lets the next call probe instead
+    pub fn release(&mut self) {
+        self.probing = None;
+    }
+
+    fn open(&mut self, now: Instant) {
+        self.state = State::Open;
+        self.opened_at = Some(now);
+    }
+}
+
+// Breakers of every provider, shared by all connections
+#[derive(Clone)]
+pub struct Breakers {
+    config: BreakerConfig,
+    breakers: Rc<RefCell<HashMap<&'static str, CircuitBreaker>>>
+}
+
+impl Breakers {
+    pub fn new(config: BreakerConfig) -> Breakers {
+        Breakers { config: config, breakers: Rc::new(RefCell::new(HashMap::new())) }
+    }
+
+    // Current breaker of the provider, a closed one for providers not called yet
+    pub fn get(&self, provider: &'static str) -> CircuitBreaker {
+        self.breakers.borrow().get(provider).cloned().unwrap_or_else(|| CircuitBreaker::new(self.config))
+    }
+
+    // Makes the call unless the provider's breaker is open, in which case
+    // `ApiError::CircuitOpen` is returned right away. Not finding the location
+    // is the provider working fine, rate limits and quotas don't count either way,
+    // every other error counts as a failure
+    pub fn call<T: 'static, F: FnOnce() -> ApiFuture<T>>(&self, provider: &'static str, request: F) -> ApiFuture<T> {
+        let allowed = self.breakers.borrow_mut()
+            .entry(provider)
+            .or_insert_with(|| CircuitBreaker::new(self.config))
+            .allow(Instant::now());
+
+        if !allowed {
+            return Box::new(futures::future::ok(Err(ApiError::CircuitOpen)));
+        }
+
+        let breakers = self.breakers.clone();
+        Box::new(request().then(move |result| {
+            let failed = match result {
+                Ok(Ok(_)) | Ok(Err(ApiError::LocationNotFound)) | Ok(Err(ApiError::LocationMismatch)) => Some(false),
+                Ok(Err(ApiError::RateLimited(_))) | Ok(Err(ApiError::QuotaExceeded)) | Ok(Err(ApiError::BudgetExhausted)) => None,
+                _ => Some(true)
+            };
+            if let Some(breaker) = breakers.borrow_mut().get_mut(provider) {
+                match failed {
+                    Some(failed) => breaker.record(failed, Instant::now()),
+                    None => breaker.release()
+                }
+            }
+            result
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::futures::future;
+    use std::cell::Cell;
+
+    fn config() -> BreakerConfig {
+        BreakerConfig { failure_rate: 0.5, window: 4, min_calls: 4, open_for: Duration::from_secs(30) }
+    }
+
+    #[test]
+    fn it_opens_on_failure_rate() {
+        let now = Instant::now();
+        let mut breaker = CircuitBreaker::new(config());
+
+        for failed in vec![false, true, false] {
+            assert!(breaker.allow(now));
+            breaker.record(failed, now);
+        }
+        assert_eq!(breaker.state(now), State::Closed);
+
+        breaker.record(true, now);
+        assert_eq!(breaker.state(now), State::Open);
+        assert!(!breaker.allow(now));
+        assert_eq!(breaker.stats(), Stats { successes: 2, failures: 2, rejected: 1 });
+    }
+
+    #[test]
+    fn it_probes_after_open_period() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(31);
+        let mut breaker = CircuitBreaker::new(config());
+        for _ in 0..4 {
+            breaker.record(true, now);
+        }
+
+        assert_eq!(breaker.state(later), State::HalfOpen);
+        assert!(breaker.allow(later));
+        assert!(!breaker.allow(later));
+
+        // lost probes are replaced after the open period
+        assert!(breaker.allow(later + Duration::from_secs(30)));
+
+        // failed probe opens the breaker for another period
+        breaker.record(true, later);
+        assert_eq!(breaker.state(later), State::Open);
+
+        let even_later = later + Duration::from_secs(31);
+        assert!(breaker.allow(even_later));
+        breaker.record(false, even_later);
+        assert_eq!(breaker.state(even_later), State::Closed);
+        assert_eq!(breaker.failure_rate(), 0.0);
+    }
+
+    #[test]
+    fn open_breaker_skips_calls() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let breakers = Breakers::new(BreakerConfig { min_calls: 2, ..config() });
+        let calls = Rc::new(Cell::new(0));
+
+        for _ in 0..3 {
+            let calls = calls.clone();
+            let result = core.run(breakers.call("apixu", move || -> ApiFuture<f32> {
+                calls.set(calls.get() + 1);
+                Box::new(future::ok(Err(ApiError::Timeout)))
+            })).unwrap();
+            assert!(result.is_err());
+        }
+
+        assert_eq!(calls.get(), 2);
+        assert_eq!(breakers.get("apixu").state(Instant::now()), State::Open);
+        assert_eq!(breakers.get("owm").state(Instant::now()), State::Closed);
+
+        let not_found = core.run(breakers.call("owm", || -> ApiFuture<f32> { Box::new(future::ok(Err(ApiError::LocationNotFound))) })).unwrap();
+        assert_eq!(not_found, Err(ApiError::LocationNotFound));
+        assert_eq!(breakers.get("owm").stats().successes, 1);
+
+        let limited = core.run(breakers.call("owm", || -> ApiFuture<f32> { Box::new(future::ok(Err(ApiError::QuotaExceeded))) })).unwrap();
+        assert_eq!(limited, Err(ApiError::QuotaExceeded));
+        assert_eq!(breakers.get("owm").stats(), Stats { successes: 1, failures: 0, rejected: 0 });
+    }
+}
@@ -0,0 +1,451 @@
+// Keeps provider calls within limits of the free-tier keys, which get
+// suspended once they exceed them: a token bucket refilled at `per_minute`
+// calls a minute and a count of calls made during the UTC day, for every key
+
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use self::futures::{Future, Stream};
+use self::tokio_core::reactor::{Handle, Interval, Timeout};
+
+use async_request::ApiFuture;
+use async_request::client::Meter;
+use async_request::error::ApiError;
+use store::{self, Record};
+use unix_now;
+
+const BUDGET: &'static str = "budget";
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// What happens to calls a provider has no budget for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exhausted {
+    // wait for the next call of the minute, calls over the daily budget are skipped
+    Queue,
+    Skip,
+    // the last response of the provider is served even if it has expired
+    ServeCache
+}
+
+impl Exhausted {
+    pub fn parse(s: &str) -> Option<Exhausted> {
+        match s.to_lowercase().as_str() {
+            "queue" => Some(Exhausted::Queue),
+            "skip" => Some(Exhausted::Skip),
+            "cache" | "serve_cache" => Some(Exhausted::ServeCache),
+            _ => None
+        }
+    }
+}
+
+// None is unlimited
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Limits {
+    pub per_minute: Option<u32>,
+    pub per_day: Option<u32>
+}
+
+impl Limits {
+    // Share of the limits left to one of `replicas` servers using the same keys,
+    // every server may make at least a call
+    pub fn share(&self, replicas: u32) -> Limits {
+        let share = |limit: u32| (limit / replicas.max(1)).max(1);
+        Limits { per_minute: self.per_minute.map(share), per_day: self.per_day.map(share) }
+    }
+}
+
+// Limits of every key of providers, the ones not listed use the default ones.
+// The limits are shared by `replicas` servers using the same keys.
+// With `path` set counters are saved there every `flush_interval` and restored on startup
+#[derive(Debug, Clone, PartialEq)]
+pub struct BudgetConfig {
+    pub default: Limits,
+    pub providers: HashMap<String, Limits>,
+    pub replicas: u32,
+    pub on_exhausted: Exhausted,
+    pub path: Option<PathBuf>,
+    pub flush_interval: Duration
+}
+
+impl Default for BudgetConfig {
+    fn default() -> BudgetConfig {
+        BudgetConfig {
+            default: Limits::default(),
+            providers: HashMap::new(),
+            replicas: 1,
+            on_exhausted: Exhausted::Skip,
+            path: None,
+            flush_interval: Duration::from_secs(10)
+        }
+    }
+}
+
+impl BudgetConfig {
+    // Limits of a key of the provider this server may use
+    pub fn limits(&self, provider: &str) -> Limits {
+        self.providers.get(provider).cloned().unwrap_or(self.default).share(self.replicas)
+    }
+}
+
+// Why a call can't be made now
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shortage {
+    // the next call of the minute is available after the delay
+    Minute(Duration),
+    Day
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Budget {
+    limits: Limits,
+    tokens: f64,
+    refilled_at: Instant,
+    // UTC day `calls` were made on, in days since the epoch
+    day: u64,
+    calls: u32
+}
+
+impl Budget {
+    pub fn new(limits: Limits, now: Instant, today: u64) -> Budget {
+        Budget {
+            limits: limits,
+            tokens: limits.per_minute.unwrap_or(0) as f64,
+            refilled_at: now,
+            day: today,
+            calls: 0
+        }
+    }
+
+    fn refill(&mut self, now: Instant, today: u64) {
+        if let Some(per_minute) = self.limits.per_minute {
+            let elapsed = now.duration_since(self.refilled_at);
+            let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+            self.tokens = (self.tokens + elapsed * per_minute as f64 / 60.0).min(per_minute as f64);
+        }
+        self.refilled_at = now;
+
+        if today != self.day {
+            self.day = today;
+            self.calls = 0;
+        }
+    }
+
+    // Takes a call out of the budget
+    pub fn take(&mut self, now: Instant, today: u64) -> Result<(), Shortage> {
+        self.refill(now, today);
+
+        if self.limits.per_day.map(|limit| self.calls >= limit).unwrap_or(false) {
+            return Err(Shortage::Day);
+        }
+
+        if let Some(per_minute) = self.limits.per_minute {
+            if self.tokens < 1.0 {
+                let wait = (1.0 - self.tokens) * 60.0 / per_minute as f64;
+                return Err(Shortage::Minute(Duration::from_millis((wait * 1000.0).ceil() as u64)));
+            }
+            self.tokens -= 1.0;
+        }
+
+        self.calls += 1;
+        Ok(())
+    }
+
+    pub fn calls_today(&self, today: u64) -> u32 {
+        if self.day == today { self.calls } else { 0 }
+    }
+
+    // None without a daily limit
+    pub fn remaining_today(&self, today: u64) -> Option<u32> {
+        self.limits.per_day.map(|limit| limit.saturating_sub(self.calls_today(today)))
+    }
+
+    fn to_record(&self, provider: &str, key: &str, now: Instant, unix: u64) -> Record {
+        let mut budget = self.clone();
+        budget.refill(now, unix / SECONDS_PER_DAY);
+
+        Record {
+            kind: BUDGET.to_owned(),
+            provider: provider.to_owned(),
+            location: String::new(),
+            stored_at: unix,
+            value: json!({ "key": key, "day": budget.day, "calls": budget.calls, "tokens": budget.tokens })
+        }
+    }
+
+    // Counters saved by `to_record`, tokens are refilled for the time passed since
+    fn restore(&mut self, record: &Record, now: Instant, unix: u64) -> Option<()> {
+        let day = record.value["day"].as_u64()?;
+        let calls = record.value["calls"].as_u64()?;
+        let tokens = record.value["tokens"].as_f64()?;
+
+        if let Some(per_minute) = self.limits.per_minute {
+            let elapsed = unix.saturating_sub(record.stored_at) as f64;
+            self.tokens = (tokens + elapsed * per_minute as f64 / 60.0).min(per_minute as f64);
+        }
+        self.refilled_at = now;
+        if day == unix / SECONDS_PER_DAY {
+            self.day = day;
+            self.calls = calls as u32;
+        }
+        Some(())
+    }
+}
+
+// Budgets of every key of providers by provider and key id, shared by all connections
+#[derive(Clone)]
+pub struct Budgets {
+    config: Rc<BudgetConfig>,
+    budgets: Rc<RefCell<HashMap<(String, String), Budget>>>,
+    handle: Handle
+}
+
+impl Budgets {
+    pub fn new(handle: &Handle, config: BudgetConfig) -> Budgets {
+        Budgets { config: Rc::new(config), budgets: Rc::new(RefCell::new(HashMap::new())), handle: handle.clone() }
+    }
+
+    pub fn on_exhausted(&self) -> Exhausted {
+        self.config.on_exhausted
+    }
+
+    // Current budget of the provider's key, a full one for keys not used yet
+    pub fn get(&self, provider: &str, key: &str) -> Budget {
+        self.budgets.borrow().get(&(provider.to_owned(), key.to_owned())).cloned()
+            .unwrap_or_else(|| Budget::new(self.config.limits(provider), Instant::now(), today()))
+    }
+
+    // Calls made today with every key of the provider
+    pub fn calls_today(&self, provider: &str) -> u32 {
+        let today = today();
+        self.budgets.borrow().iter().filter(|&(&(ref p, _), _)| p == provider).map(|(_, budget)| budget.calls_today(today)).sum()
+    }
+
+    fn take(&self, provider: &str, key: &str) -> Result<(), Shortage> {
+        let (now, today) = (Instant::now(), today());
+        let config = &self.config;
+        self.budgets.borrow_mut()
+            .entry((provider.to_owned(), key.to_owned()))
+            .or_insert_with(|| Budget::new(config.limits(provider), now, today))
+            .take(now, today)
+    }
+
+    // Makes the call once the budget of the provider's key allows it. Calls over
+    // the minute's budget wait for the next one with `Exhausted::Queue` and fail
+    // with `ApiError::RateLimited` otherwise, calls over the daily budget fail
+    // with `ApiError::BudgetExhausted`
+    pub fn call<T, F>(&self, provider: &'static str, key: &str, request: F) -> ApiFuture<T>
+        where T: 'static, F: FnOnce() -> ApiFuture<T> + 'static {
+        match self.take(provider, key) {
+            Ok(()) => request(),
+            Err(Shortage::Day) => Box::new(futures::future::ok(Err(ApiError::BudgetExhausted))),
+            Err(Shortage::Minute(wait)) => match (self.config.on_exhausted, Timeout::new(wait, &self.handle)) {
+                (Exhausted::Queue, Ok(timeout)) => {
+                    let (budgets, key) = (self.clone(), key.to_owned());
+                    Box::new(timeout.map_err(hyper::Error::from).and_then(move |_| budgets.call(provider, &key, request)))
+                },
+                _ => Box::new(futures::future::ok(Err(ApiError::RateLimited(Some(wait)))))
+            }
+        }
+    }
+
+    // Meter taking every upstream attempt made with the provider's key out of
+    // the key's budget, so that retries count and requests shared by several callers count once
+    pub fn meter(&self, provider: &'static str, key: &str) -> Meter {
+        let (budgets, key) = (self.clone(), key.to_owned());
+        Rc::new(move || budgets.call(provider, &key, || -> ApiFuture<()> { Box::new(futures::future::ok(Ok(()))) }))
+    }
+
+    pub fn records(&self) -> Vec<Record> {
+        let (now, unix) = (Instant::now(), unix_now());
+        self.budgets.borrow().iter().map(|(&(ref provider, ref key), budget)| budget.to_record(provider, key, now, unix)).collect()
+    }
+
+    // Restores counters of keys from budget records, returns how many were restored
+    pub fn restore(&self, records: &[Record]) -> usize {
+        let (now, unix) = (Instant::now(), unix_now());
+        let mut budgets = self.budgets.borrow_mut();
+
+        records.iter().filter(|r| r.kind == BUDGET).filter(|record| {
+            let key = match record.value["key"].as_str() {
+                Some(key) => key.to_owned(),
+                None => return false
+            };
+            let mut budget = Budget::new(self.config.limits(&record.provider), now, unix / SECONDS_PER_DAY);
+            let restored = budget.restore(record, now, unix).is_some();
+            if restored {
+                budgets.insert((record.provider.clone(), key), budget);
+            }
+            restored
+        }).count()
+    }
+}
+
+fn today() -> u64 {
+    unix_now() / SECONDS_PER_DAY
+}
+
+// Time until the UTC day rolls over and daily budgets are renewed
+pub fn rest_of_day() -> Duration {
+    Duration::from_secs(SECONDS_PER_DAY - unix_now() % SECONDS_PER_DAY)
+}
+
+// Restores counters from `path` and schedules their snapshots,
+// does nothing when no path is configured
+pub fn persist(handle: &Handle, budgets: &Budgets) {
+    let path = match budgets.config.path {
+        Some(ref path) => path.clone(),
+        None => return
+    };
+
+    match store::load(&path) {
+        Ok(records) => {
+            let restored = budgets.restore(&records);
+            println!("Restored {} provider budgets from {}", restored, path.display());
+        },
+        Err(e) => println!("Failed to load provider budgets from {}: {}", path.display(), e)
+    }
+
+    let writer = match store::Writer::new(path.clone(), u64::max_value(), "provider budgets") {
+        Ok(writer) => writer,
+        Err(e) => return println!("Failed to start saving provider budgets to {}: {}", path.display(), e)
+    };
+
+    let saved = budgets.clone();
+    let snapshots = Interval::new(budgets.config.flush_interval, handle).unwrap().for_each(move |_| {
+        writer.save(saved.records());
+        Ok(())
+    });
+
+    handle.spawn(snapshots.map_err(|e| println!("budget snapshots stopped: {:?}", e)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::futures::future;
+    use std::cell::Cell;
+
+    fn limits(per_minute: Option<u32>, per_day: Option<u32>) -> Limits {
+        Limits { per_minute: per_minute, per_day: per_day }
+    }
+
+    #[test]
+    fn it_refills_tokens_over_time() {
+        let now = Instant::now();
+        let mut budget = Budget::new(limits(Some(2), None), now, 17000);
+
+        assert_eq!(budget.take(now, 17000), Ok(()));
+        assert_eq!(budget.take(now, 17000), Ok(()));
+        assert_eq!(budget.take(now, 17000), Err(Shortage::Minute(Duration::from_secs(30))));
+
+        let later = now + Duration::from_secs(15);
+        assert_eq!(budget.take(later, 17000), Err(Shortage::Minute(Duration::from_secs(15))));
+        assert_eq!(budget.take(later + Duration::from_secs(15), 17000), Ok(()));
+        assert_eq!(budget.calls_today(17000), 3);
+    }
+
+    #[test]
+    fn replicas_share_limits() {
+        let config = BudgetConfig { default: limits(Some(60), Some(500)), replicas: 3, ..BudgetConfig::default() };
+        assert_eq!(config.limits("owm"), limits(Some(20), Some(166)));
+        assert_eq!(limits(Some(2), None).share(3), limits(Some(1), None));
+        assert_eq!(limits(Some(2), None).share(0), limits(Some(2), None));
+    }
+
+    #[test]
+    fn daily_budget_resets_next_day() {
+        let now = Instant::now();
+        let mut budget = Budget::new(limits(None, Some(2)), now, 17000);
+
+        assert!(budget.take(now, 17000).is_ok());
+        assert!(budget.take(now, 17000).is_ok());
+        assert_eq!(budget.take(now, 17000), Err(Shortage::Day));
+        assert_eq!(budget.remaining_today(17000), Some(0));
+
+        assert_eq!(budget.remaining_today(17001), Some(2));
+        assert!(budget.take(now, 17001).is_ok());
+        assert_eq!(budget.calls_today(17001), 1);
+    }
+
+    #[test]
+    fn counters_survive_restarts() {
+        let now = Instant::now();
+        let unix = 17000 * SECONDS_PER_DAY + 600;
+        let mut budget = Budget::new(limits(Some(60), Some(100)), now, 17000);
+        for _ in 0..60 {
+            budget.take(now, 17000).unwrap();
+        }
+        let record = budget.to_record("owm", "cbf29ce484222325", now, unix);
+
+        // ten seconds after the snapshot ten tokens are back
+        let mut restored = Budget::new(limits(Some(60), Some(100)), now, 17000);
+        restored.restore(&record, now, unix + 10).unwrap();
+        assert_eq!(restored.remaining_today(17000), Some(40));
+        assert!((restored.tokens - 10.0).abs() < 0.001);
+
+        let mut next_day = Budget::new(limits(Some(60), Some(100)), now, 17001);
+        next_day.restore(&record, now, unix + SECONDS_PER_DAY).unwrap();
+        assert_eq!(next_day.remaining_today(17001), Some(100));
+    }
+
+    #[test]
+    fn calls_over_budget_are_skipped_or_queued() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let calls = Rc::new(Cell::new(0));
+        let request = |calls: &Rc<Cell<u32>>| {
+            let calls = calls.clone();
+            move || -> ApiFuture<f32> {
+                calls.set(calls.get() + 1);
+                Box::new(future::ok(Ok(-14.0)))
+            }
+        };
+
+        let skipping = Budgets::new(&core.handle(), BudgetConfig { default: limits(Some(1), None), ..BudgetConfig::default() });
+        assert_eq!(core.run(skipping.call("owm", "key", request(&calls))).unwrap(), Ok(-14.0));
+        match core.run(skipping.call("owm", "key", request(&calls))).unwrap() {
+            Err(ApiError::RateLimited(Some(wait))) => assert!(wait > Duration::from_secs(59)),
+            other => panic!("unexpected {:?}", other)
+        }
+        assert_eq!(calls.get(), 1);
+
+        // a token every 100ms, the bucket starts empty
+        let queueing = Budgets::new(&core.handle(), BudgetConfig { default: limits(Some(600), Some(3)), on_exhausted: Exhausted::Queue, ..BudgetConfig::default() });
+        let empty = Budget { tokens: 0.0, ..queueing.get("apixu", "key") };
+        queueing.budgets.borrow_mut().insert(("apixu".to_owned(), "key".to_owned()), empty);
+
+        let started = Instant::now();
+        assert_eq!(core.run(queueing.call("apixu", "key", request(&calls))).unwrap(), Ok(-14.0));
+        assert!(started.elapsed() >= Duration::from_millis(90));
+        assert_eq!(core.run(queueing.call("apixu", "key", request(&calls))).unwrap(), Ok(-14.0));
+        assert_eq!(core.run(queueing.call("apixu", "key", request(&calls))).unwrap(), Ok(-14.0));
+
+        // calls over the daily budget aren't queued
+        assert_eq!(core.run(queueing.call("apixu", "key", request(&calls))).unwrap(), Err(ApiError::BudgetExhausted));
+        assert_eq!(calls.get(), 4);
+    }
+
+    #[test]
+    fn meters_take_attempts_out_of_key_budgets() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let budgets = Budgets::new(&core.handle(), BudgetConfig { default: limits(None, Some(2)), ..BudgetConfig::default() });
+        let (first, second) = (budgets.meter("weatherbit", "first"), budgets.meter("weatherbit", "second"));
+
+        assert_eq!(core.run(first()).unwrap(), Ok(()));
+        assert_eq!(core.run(first()).unwrap(), Ok(()));
+        assert_eq!(core.run(first()).unwrap(), Err(ApiError::BudgetExhausted));
+        assert_eq!(core.run(second()).unwrap(), Ok(()));
+        assert_eq!(budgets.calls_today("weatherbit"), 3);
+
+        let restored = Budgets::new(&core.handle(), BudgetConfig { default: limits(None, Some(2)), ..BudgetConfig::default() });
+        assert_eq!(restored.restore(&budgets.records()), 2);
+        assert_eq!(restored.get("weatherbit", "first").remaining_today(today()), Some(0));
+        assert_eq!(restored.get("weatherbit", "second").remaining_today(today()), Some(1));
+    }
+}
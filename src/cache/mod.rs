@@ -0,0 +1,368 @@
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use self::futures::Future;
+
+use async_request::ApiFuture;
+use provider::{ProviderResult, timed};
+use location::Location;
+use observation::CurrentObservation;
+use forecast::Forecast;
+use geocoding::CanonicalLocation;
+use config::CacheConfig;
+use store::{Stored, Record};
+use unix_now;
+
+const CURRENT: &'static str = "current";
+const FORECAST: &'static str = "forecast";
+const LOCATIONS: &'static str = "locations";
+const AGGREGATES: &'static str = "aggregates";
+
+// Providers are always asked in metric units and values are converted
+// after aggregation, so requests in every unit system share an entry
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub provider: String,
+    pub location: String
+}
+
+impl CacheKey {
+    pub fn new(provider: &str, location: &Location) -> CacheKey {
+        CacheKey { provider: provider.to_owned(), location: normalize(location) }
+    }
+
+    // Key of the last aggregate of the location, aggregates of every strategy are kept apart
+    pub fn aggregate(aggregation: &str, location: &Location) -> CacheKey {
+        CacheKey::new(&format!("aggregate:{}", aggregation), location)
+    }
+}
+
+// Location in the form equal queries share, coordinates are rounded to about a kilometre
+pub fn normalize(location: &Location) -> String {
+    match *location {
+        Location::Name(ref name) => format!("name:{}", name.trim().to_lowercase()),
+        Location::Coordinates { lat, lon } => format!("coordinates:{:.2},{:.2}", lat, lon),
+        Location::PostalCode { ref code, ref country } => format!("postal_code:{},{}",
+            code.trim().to_lowercase(),
+            country.as_ref().map(|c| c.trim().to_lowercase()).unwrap_or_default()
+        ),
+        Location::Ip(ref ip) => format!("ip:{}", ip),
+        Location::Auto => "auto".to_owned()
+    }
+}
+
+struct Entry<T> {
+    value: T,
+    stored_at: Instant
+}
+
+// Bounded map of values expiring `ttl` after they were stored,
+// clones share the entries
+#[derive(Clone)]
+pub struct Cache<T> {
+    ttl: Duration,
+    capacity: usize,
+    entries: Rc<RefCell<HashMap<CacheKey, Entry<T>>>>
+}
+
+impl<T: Clone + 'static> Cache<T> {
+    pub fn new(ttl: Duration, capacity: usize) -> Cache<T> {
+        Cache {
+            ttl: ttl,
+            capacity: capacity,
+            entries: Rc::new(RefCell::new(HashMap::new()))
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Stored value along with its age, unless it has expired
+    pub fn get(&self, key: &CacheKey) -> Option<(T, Duration)> {
+        self.get_within(key, self.ttl)
+    }
+
+    // Stored value younger than `max_age`, expired ones are served until evicted
+    pub fn get_within(&self, key: &CacheKey, max_age: Duration) -> Option<(T, Duration)> {
+        let entries = self.entries.borrow();
+
+        entries.get(key).and_then(|entry| {
+            let age = entry.stored_at.elapsed();
+            if age < max_age {
+                Some((entry.value.clone(), age))
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn insert(&self, key: CacheKey, value: T) {
+        self.insert_at(key, value, Instant::now())
+    }
+
+    // When the cache is full expired entries are dropped first, then the oldest one
+    fn insert_at(&self, key: CacheKey, value: T, stored_at: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.borrow_mut();
+
+        if !entries.contains_key(&key) && entries.len() >= self.capacity {
+            let ttl = self.ttl;
+            entries.retain(|_, entry| entry.stored_at.elapsed() < ttl);
+
+            if entries.len() >= self.capacity {
+                let oldest = entries.iter().min_by_key(|&(_, entry)| entry.stored_at).map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+
+        entries.insert(key, Entry { value: value, stored_at: stored_at });
+    }
+
+    // Provider call answered from the cache when possible,
+    // successful responses are stored for the next calls
+    pub fn fetch<F>(&self, provider: &'static str, location: &Location, request: F) -> Box<Future<Item = ProviderResult<T>, Error = hyper::Error>>
+        where F: FnOnce() -> ApiFuture<T> {
+        let key = CacheKey::new(provider, location);
+
+        if let Some((value, age)) = self.get(&key) {
+            return Box::new(futures::future::ok(ProviderResult {
+                name: provider,
+                result: Ok(value),
+                latency: Duration::from_secs(0),
+                age: Some(age)
+            }));
+        }
+
+        let cache = self.clone();
+        Box::new(timed(provider, request()).map(move |r| {
+            if let Ok(ref value) = r.result {
+                cache.insert(key, value.clone());
+            }
+            r
+        }))
+    }
+}
+
+impl<T: Clone + Stored + 'static> Cache<T> {
+    // Live entries as records of `kind`
+    pub fn records(&self, kind: &str) -> Vec<Record> {
+        let now = unix_now();
+
+        self.entries.borrow().iter()
+            .filter(|&(_, entry)| entry.stored_at.elapsed() < self.ttl)
+            .map(|(key, entry)| Record {
+                kind: kind.to_owned(),
+                provider: key.provider.clone(),
+                location: key.location.clone(),
+                stored_at: now.saturating_sub(entry.stored_at.elapsed().as_secs()),
+                value: entry.value.to_stored()
+            })
+            .collect()
+    }
+
+    // Puts back records of `kind` keeping their age, expired ones are skipped.
+    // Returns number of restored entries
+    pub fn restore(&self, records: &[Record], kind: &str) -> usize {
+        let now = unix_now();
+        let mut restored = 0;
+
+        for record in records.iter().filter(|r| r.kind == kind) {
+            let age = Duration::from_secs(now.saturating_sub(record.stored_at));
+            let stored_at = match Instant::now().checked_sub(age) {
+                Some(stored_at) if age < self.ttl => stored_at,
+                _ => continue
+            };
+            let value = match T::from_stored(&record.value) {
+                Some(value) => value,
+                None => continue
+            };
+
+            let key = CacheKey { provider: record.provider.clone(), location: record.location.clone() };
+            self.insert_at(key, value, stored_at);
+            restored += 1;
+        }
+
+        restored
+    }
+}
+
+// Caches shared by all connections, one per kind of upstream call.
+// Last aggregated observations are kept for the max-stale window to be
+// served when every provider fails
+#[derive(Clone)]
+pub struct ResponseCache {
+    pub current: Cache<CurrentObservation>,
+    pub forecast: Cache<Forecast>,
+    pub locations: Cache<CanonicalLocation>,
+    pub aggregates: Cache<CurrentObservation>,
+    pub revalidate_after: Duration,
+    revalidating: Rc<RefCell<HashSet<CacheKey>>>
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> ResponseCache {
+        ResponseCache {
+            current: Cache::new(config.current_ttl, config.capacity),
+            forecast: Cache::new(config.forecast_ttl, config.capacity),
+            locations: Cache::new(config.location_ttl, config.capacity),
+            aggregates: Cache::new(config.max_stale, config.capacity),
+            revalidate_after: config.revalidate_after,
+            revalidating: Rc::new(RefCell::new(HashSet::new()))
+        }
+    }
+
+    // Marks the key as being revalidated, false if it already is
+    pub fn begin_revalidation(&self, key: &CacheKey) -> bool {
+        self.revalidating.borrow_mut().insert(key.clone())
+    }
+
+    pub fn end_revalidation(&self, key: &CacheKey) {
+        self.revalidating.borrow_mut().remove(key);
+    }
+
+    pub fn records(&self) -> Vec<Record> {
+        let mut records = self.current.records(CURRENT);
+        records.extend(self.forecast.records(FORECAST));
+        records.extend(self.locations.records(LOCATIONS));
+        records.extend(self.aggregates.records(AGGREGATES));
+        records
+    }
+
+    pub fn restore(&self, records: &[Record]) -> usize {
+        self.current.restore(records, CURRENT)
+            + self.forecast.restore(records, FORECAST)
+            + self.locations.restore(records, LOCATIONS)
+            + self.aggregates.restore(records, AGGREGATES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::thread;
+    use async_request::error::ApiError;
+
+    fn key(location: &str) -> CacheKey {
+        CacheKey::new("owm", &Location::Name(location.to_owned()))
+    }
+
+    #[test]
+    fn it_normalizes_locations() {
+        assert_eq!(normalize(&Location::Name(" Tomsk ".to_owned())), normalize(&Location::Name("tomsk".to_owned())));
+        assert_eq!(normalize(&Location::Coordinates { lat: 56.501, lon: 84.969 }), "coordinates:56.50,84.97");
+        assert_eq!(normalize(&Location::PostalCode { code: "94040".to_owned(), country: Some("US".to_owned()) }), "postal_code:94040,us");
+    }
+
+    #[test]
+    fn it_expires_entries() {
+        let fresh: Cache<f32> = Cache::new(Duration::from_secs(60), 10);
+        let expired: Cache<f32> = Cache::new(Duration::from_secs(0), 10);
+
+        fresh.insert(key("tomsk"), -14.0);
+        expired.insert(key("tomsk"), -14.0);
+
+        assert_eq!(fresh.get(&key("tomsk")).map(|(v, _)| v), Some(-14.0));
+        assert_eq!(fresh.get(&key("perm")), None);
+        assert_eq!(expired.get(&key("tomsk")), None);
+    }
+
+    #[test]
+    fn it_evicts_oldest_entries_when_full() {
+        let cache: Cache<f32> = Cache::new(Duration::from_secs(60), 2);
+
+        cache.insert(key("tomsk"), -14.0);
+        thread::sleep(Duration::from_millis(1));
+        cache.insert(key("perm"), -5.0);
+        thread::sleep(Duration::from_millis(1));
+        cache.insert(key("ufa"), -3.0);
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&key("tomsk")), None);
+        assert!(cache.get(&key("perm")).is_some());
+        assert!(cache.get(&key("ufa")).is_some());
+    }
+
+    #[test]
+    fn it_restores_records_keeping_their_age() {
+        let cache: Cache<CurrentObservation> = Cache::new(Duration::from_secs(600), 10);
+        let tomsk = CacheKey::new("owm", &Location::Name("tomsk".to_owned()));
+        cache.insert(tomsk.clone(), CurrentObservation::new(-14.0));
+
+        let mut records = cache.records("current");
+        records[0].stored_at -= 120;
+        let mut expired = records[0].clone();
+        expired.location = "name:perm".to_owned();
+        expired.stored_at -= 3600;
+        records.push(expired);
+
+        let restored: Cache<CurrentObservation> = Cache::new(Duration::from_secs(600), 10);
+        assert_eq!(restored.restore(&records, "forecast"), 0);
+        assert_eq!(restored.restore(&records, "current"), 1);
+
+        let (value, age) = restored.get(&tomsk).unwrap();
+        assert_eq!(value, CurrentObservation::new(-14.0));
+        assert!(age >= Duration::from_secs(120) && age < Duration::from_secs(130));
+    }
+
+    #[test]
+    fn revalidation_runs_once_per_location() {
+        let cache = ResponseCache::new(&CacheConfig::default());
+        let tomsk = CacheKey::aggregate("mean", &Location::Name("tomsk".to_owned()));
+        let perm = CacheKey::aggregate("mean", &Location::Name("perm".to_owned()));
+        let tomsk_median = CacheKey::aggregate("median", &Location::Name("tomsk".to_owned()));
+
+        assert!(cache.begin_revalidation(&tomsk));
+        assert!(!cache.clone().begin_revalidation(&tomsk));
+        assert!(cache.begin_revalidation(&perm));
+        assert!(cache.begin_revalidation(&tomsk_median));
+
+        cache.end_revalidation(&tomsk);
+        assert!(cache.begin_revalidation(&tomsk));
+    }
+
+    #[test]
+    fn fetch_reuses_successful_responses() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let cache: Cache<f32> = Cache::new(Duration::from_secs(60), 10);
+        let calls = Cell::new(0);
+        let tomsk = Location::Name("Tomsk".to_owned());
+
+        let request = || -> ApiFuture<f32> {
+            calls.set(calls.get() + 1);
+            Box::new(futures::future::ok(Ok(-14.0)))
+        };
+
+        let first = core.run(cache.fetch("owm", &tomsk, &request)).unwrap();
+        let second = core.run(cache.fetch("owm", &tomsk, &request)).unwrap();
+
+        assert_eq!(calls.get(), 1);
+        assert_eq!(first.age, None);
+        assert_eq!(second.result, Ok(-14.0));
+        assert!(second.age.is_some());
+
+        let failing = cache.fetch("apixu", &tomsk, || Box::new(futures::future::ok(Err(ApiError::Timeout))));
+        assert_eq!(core.run(failing).unwrap().result, Err(ApiError::Timeout));
+        assert!(cache.get(&CacheKey::new("apixu", &tomsk)).is_none());
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::env::var;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use aggregation::{AggregationConfig, ConfidenceThresholds, Strategy, parse_weights};
+use quorum::QuorumConfig;
+use async_request::client::ClientConfig;
+use async_request::retry::{RetryConfig, RetryPolicy};
+use breaker::BreakerConfig;
+use budget::{BudgetConfig, Exhausted, Limits};
+use keys::{KeysConfig, Rotation};
+
+// Providers whose settings may be overridden separately, like RETRY_ATTEMPTS_APIXU or BUDGET_PER_DAY_OWM
+const PROVIDERS: [&'static str; 3] = ["owm", "apixu", "weatherbit"];
+
+// Server settings, every value has a default and may be overridden by environment variables
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub cache: CacheConfig,
+    pub aggregation: AggregationConfig,
+    pub quorum: QuorumConfig,
+    pub client: ClientConfig,
+    pub breaker: BreakerConfig,
+    pub budget: BudgetConfig,
+    pub keys: KeysConfig
+}
+
+// How long provider responses are reused and how many of them are kept per endpoint.
+// With `path` set the cache is saved there every `flush_interval` and restored on startup.
+// Aggregates are served up to `max_stale` old when every provider fails,
+// providers are asked again `revalidate_after` such a response
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheConfig {
+    pub current_ttl: Duration,
+    pub forecast_ttl: Duration,
+    pub location_ttl: Duration,
+    pub capacity: usize,
+    pub path: Option<PathBuf>,
+    pub max_bytes: u64,
+    pub flush_interval: Duration,
+    pub max_stale: Duration,
+    pub revalidate_after: Duration
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            current_ttl: Duration::from_secs(10 * 60),
+            forecast_ttl: Duration::from_secs(60 * 60),
+            location_ttl: Duration::from_secs(24 * 60 * 60),
+            capacity: 1000,
+            path: None,
+            max_bytes: 10 * 1024 * 1024,
+            flush_interval: Duration::from_secs(60),
+            max_stale: Duration::from_secs(6 * 60 * 60),
+            revalidate_after: Duration::from_secs(30)
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config, String> {
+        Config::from_lookup(|name| var(name).ok())
+    }
+
+    // TTLs and intervals are given in seconds:
+    // CACHE_CURRENT_TTL, CACHE_FORECAST_TTL, CACHE_LOCATION_TTL, CACHE_CAPACITY,
+    // CACHE_PATH, CACHE_MAX_BYTES, CACHE_FLUSH_INTERVAL, CACHE_MAX_STALE, CACHE_REVALIDATE_AFTER.
+    // AGGREGATION picks the default strategy, AGGREGATION_WEIGHTS are like "owm:1,apixu:0.5".
+    // Confidence is medium above CONFIDENCE_MEDIUM_STDDEV and low above CONFIDENCE_LOW_STDDEV °C.
+    // QUORUM is the number of providers enough to respond, DEADLINE_MS limits waiting for the rest.
+    // HTTP_IDLE_TIMEOUT closes unused upstream connections, HTTP_MAX_PER_HOST caps requests to a provider,
+    // TLS_CA_BUNDLE is a pem file with certificates to trust besides the system ones.
+    // RETRY_ATTEMPTS, RETRY_BACKOFF_MS, RETRY_MAX_BACKOFF_MS and RETRY_JITTER set the retry policy,
+    // each of them may be suffixed with a provider name to change it for that provider only.
+    // BREAKER_FAILURE_RATE of BREAKER_WINDOW last calls, at least BREAKER_MIN_CALLS of them,
+    // stop calls to a provider for BREAKER_OPEN_SECS.
+    // BUDGET_PER_MINUTE and BUDGET_PER_DAY limit provider calls, suffixed with a provider
+    // name they limit that provider only. BUDGET_EXHAUSTED is queue, skip or cache,
+    // BUDGET_PATH keeps the counters on disk, saved every BUDGET_FLUSH_INTERVAL seconds.
+    // BUDGET_REPLICAS servers sharing the keys split the limits between them.
+    // KEY_ROTATION is round_robin or least_used, keys rejected by providers
+    // are set aside for KEY_COOL_DOWN seconds
+    pub fn from_lookup<F: Fn(&str) -> Option<String>>(lookup: F) -> Result<Config, String> {
+        let defaults = CacheConfig::default();
+
+        let cache = CacheConfig {
+            current_ttl: Duration::from_secs(setting(&lookup, "CACHE_CURRENT_TTL", defaults.current_ttl.as_secs())?),
+            forecast_ttl: Duration::from_secs(setting(&lookup, "CACHE_FORECAST_TTL", defaults.forecast_ttl.as_secs())?),
+            location_ttl: Duration::from_secs(setting(&lookup, "CACHE_LOCATION_TTL", defaults.location_ttl.as_secs())?),
+            capacity: setting(&lookup, "CACHE_CAPACITY", defaults.capacity)?,
+            path: lookup("CACHE_PATH").filter(|p| !p.is_empty()).map(PathBuf::from),
+            max_bytes: setting(&lookup, "CACHE_MAX_BYTES", defaults.max_bytes)?,
+            flush_interval: Duration::from_secs(setting(&lookup, "CACHE_FLUSH_INTERVAL", defaults.flush_interval.as_secs())?),
+            max_stale: Duration::from_secs(setting(&lookup, "CACHE_MAX_STALE", defaults.max_stale.as_secs())?),
+            revalidate_after: Duration::from_secs(setting(&lookup, "CACHE_REVALIDATE_AFTER", defaults.revalidate_after.as_secs())?)
+        };
+
+        let aggregation = AggregationConfig {
+            strategy: match lookup("AGGREGATION") {
+                Some(name) => Strategy::parse(&name).ok_or(format!("Invalid AGGREGATION: {}", name))?,
+                None => Strategy::Mean
+            },
+            weights: parse_weights(&lookup("AGGREGATION_WEIGHTS").unwrap_or_default())?,
+            confidence: ConfidenceThresholds {
+                medium: setting(&lookup, "CONFIDENCE_MEDIUM_STDDEV", ConfidenceThresholds::default().medium)?,
+                low: setting(&lookup, "CONFIDENCE_LOW_STDDEV", ConfidenceThresholds::default().low)?
+            }
+        };
+
+        let quorum = QuorumConfig {
+            quorum: optional_setting(&lookup, "QUORUM")?.filter(|n| *n > 0),
+            deadline: optional_setting(&lookup, "DEADLINE_MS")?.map(Duration::from_millis)
+        };
+
+        let client_defaults = ClientConfig::default();
+        let client = ClientConfig {
+            idle_timeout: Duration::from_secs(setting(&lookup, "HTTP_IDLE_TIMEOUT", client_defaults.idle_timeout.as_secs())?),
+            max_per_host: setting(&lookup, "HTTP_MAX_PER_HOST", client_defaults.max_per_host)?,
+            ca_bundle: lookup("TLS_CA_BUNDLE").filter(|p| !p.is_empty()).map(PathBuf::from),
+            retries: retry_config(&lookup)?
+        };
+
+        let breaker_defaults = BreakerConfig::default();
+        let breaker = BreakerConfig {
+            failure_rate: setting(&lookup, "BREAKER_FAILURE_RATE", breaker_defaults.failure_rate)?,
+            window: setting(&lookup, "BREAKER_WINDOW", breaker_defaults.window)?,
+            min_calls: setting(&lookup, "BREAKER_MIN_CALLS", breaker_defaults.min_calls)?,
+            open_for: Duration::from_secs(setting(&lookup, "BREAKER_OPEN_SECS", breaker_defaults.open_for.as_secs())?)
+        };
+
+        let budget_defaults = BudgetConfig::default();
+        let (default_limits, provider_limits) = budget_limits(&lookup)?;
+        let budget = BudgetConfig {
+            default: default_limits,
+            providers: provider_limits,
+            replicas: setting(&lookup, "BUDGET_REPLICAS", budget_defaults.replicas)?.max(1),
+            on_exhausted: match lookup("BUDGET_EXHAUSTED") {
+                Some(name) => Exhausted::parse(&name).ok_or(format!("Invalid BUDGET_EXHAUSTED: {}", name))?,
+                None => budget_defaults.on_exhausted
+            },
+            path: lookup("BUDGET_PATH").filter(|p| !p.is_empty()).map(PathBuf::from),
+            flush_interval: Duration::from_secs(setting(&lookup, "BUDGET_FLUSH_INTERVAL", budget_defaults.flush_interval.as_secs())?)
+        };
+
+        let keys_defaults = KeysConfig::default();
+        let keys = KeysConfig {
+            rotation: match lookup("KEY_ROTATION") {
+                Some(name) => Rotation::parse(&name).ok_or(format!("Invalid KEY_ROTATION: {}", name))?,
+                None => keys_defaults.rotation
+            },
+            cool_down: Duration::from_secs(setting(&lookup, "KEY_COOL_DOWN", keys_defaults.cool_down.as_secs())?)
+        };
+
+        Ok(Config { cache: cache, aggregation: aggregation, quorum: quorum, client: client, breaker: breaker, budget: budget, keys: keys })
+    }
+}
+
+fn retry_config<F: Fn(&str) -> Option<String>>(lookup: &F) -> Result<RetryConfig, String> {
+    let default = retry_policy(lookup, "", RetryPolicy::default())?;
+
+    let mut providers = HashMap::new();
+    for provider in PROVIDERS.iter() {
+        let policy = retry_policy(lookup, &format!("_{}", provider.to_uppercase()), default)?;
+        if policy != default {
+            providers.insert(provider.to_string(), policy);
+        }
+    }
+
+    Ok(RetryConfig { default: default, providers: providers })
+}
+
+fn retry_policy<F: Fn(&str) -> Option<String>>(lookup: &F, suffix: &str, defaults: RetryPolicy) -> Result<RetryPolicy, String> {
+    Ok(RetryPolicy {
+        max_attempts: setting(lookup, &format!("RETRY_ATTEMPTS{}", suffix), defaults.max_attempts)?.max(1),
+        backoff: optional_setting(lookup, &format!("RETRY_BACKOFF_MS{}", suffix))?.map(Duration::from_millis).unwrap_or(defaults.backoff),
+        max_backoff: optional_setting(lookup, &format!("RETRY_MAX_BACKOFF_MS{}", suffix))?.map(Duration::from_millis).unwrap_or(defaults.max_backoff),
+        jitter: setting(lookup, &format!("RETRY_JITTER{}", suffix), defaults.jitter)?
+    })
+}
+
+// Zero limits are the same as absent ones
+fn budget_limits<F: Fn(&str) -> Option<String>>(lookup: &F) -> Result<(Limits, HashMap<String, Limits>), String> {
+    let limits = |suffix: &str, defaults: Limits| -> Result<Limits, String> {
+        Ok(Limits {
+            per_minute: optional_setting(lookup, &format!("BUDGET_PER_MINUTE{}", suffix))?.or(defaults.per_minute).filter(|n| *n > 0),
+            per_day: optional_setting(lookup, &format!("BUDGET_PER_DAY{}", suffix))?.or(defaults.per_day).filter(|n| *n > 0)
+        })
+    };
+    let default = limits("", Limits::default())?;
+
+    let mut providers = HashMap::new();
+    for provider in PROVIDERS.iter() {
+        let provider_limits = limits(&format!("_{}", provider.to_uppercase()), default)?;
+        if provider_limits != default {
+            providers.insert(provider.to_string(), provider_limits);
+        }
+    }
+
+    Ok((default, providers))
+}
+
+fn setting<T: FromStr, F: Fn(&str) -> Option<String>>(lookup: &F, name: &str, default: T) -> Result<T, String> {
+    match lookup(name) {
+        Some(value) => value.trim().parse::<T>().map_err(|_| format!("Invalid {}: {}", name, value)),
+        None => Ok(default)
+    }
+}
+
+fn optional_setting<T: FromStr, F: Fn(&str) -> Option<String>>(lookup: &F, name: &str) -> Result<Option<T>, String> {
+    match lookup(name) {
+        Some(value) => value.trim().parse::<T>().map(Some).map_err(|_| format!("Invalid {}: {}", name, value)),
+        None => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_uses_defaults_for_absent_settings() {
+        let config = Config::from_lookup(|_| None).unwrap();
+
+        assert_eq!(config.cache, CacheConfig::default());
+        assert_eq!(config.aggregation, AggregationConfig::default());
+        assert_eq!(config.quorum, QuorumConfig::default());
+        assert_eq!(config.client, ClientConfig::default());
+        assert_eq!(config.breaker, BreakerConfig::default());
+        assert_eq!(config.budget, BudgetConfig::default());
+        assert_eq!(config.keys, KeysConfig::default());
+    }
+
+    #[test]
+    fn it_reads_settings() {
+        let config = Config::from_lookup(|name| match name {
+            "CACHE_CURRENT_TTL" => Some("60".to_owned()),
+            "CACHE_CAPACITY" => Some("10".to_owned()),
+            "CACHE_PATH" => Some("/var/cache/weather/cache.json".to_owned()),
+            "AGGREGATION" => Some("weighted".to_owned()),
+            "AGGREGATION_WEIGHTS" => Some("owm:2".to_owned()),
+            "CONFIDENCE_LOW_STDDEV" => Some("4".to_owned()),
+            "QUORUM" => Some("2".to_owned()),
+            "DEADLINE_MS" => Some("1500".to_owned()),
+            "HTTP_MAX_PER_HOST" => Some("4".to_owned()),
+            "TLS_CA_BUNDLE" => Some("/etc/weather/ca.pem".to_owned()),
+            "RETRY_ATTEMPTS" => Some("2".to_owned()),
+            "RETRY_ATTEMPTS_APIXU" => Some("4".to_owned()),
+            "RETRY_BACKOFF_MS_APIXU" => Some("500".to_owned()),
+            "BREAKER_OPEN_SECS" => Some("60".to_owned()),
+            "BUDGET_PER_DAY" => Some("1000".to_owned()),
+            "BUDGET_PER_MINUTE_OWM" => Some("60".to_owned()),
+            "BUDGET_PER_DAY_WEATHERBIT" => Some("0".to_owned()),
+            "BUDGET_EXHAUSTED" => Some("cache".to_owned()),
+            "BUDGET_REPLICAS" => Some("2".to_owned()),
+            "KEY_ROTATION" => Some("least_used".to_owned()),
+            _ => None
+        }).unwrap();
+
+        assert_eq!(config.cache.current_ttl, Duration::from_secs(60));
+        assert_eq!(config.cache.forecast_ttl, CacheConfig::default().forecast_ttl);
+        assert_eq!(config.cache.capacity, 10);
+        assert_eq!(config.cache.path, Some(PathBuf::from("/var/cache/weather/cache.json")));
+        assert_eq!(config.aggregation.strategy, Strategy::WeightedMean);
+        assert_eq!(config.aggregation.weights.get("owm"), Some(&2.0));
+        assert_eq!(config.aggregation.confidence, ConfidenceThresholds { medium: 1.0, low: 4.0 });
+        assert_eq!(config.quorum, QuorumConfig { quorum: Some(2), deadline: Some(Duration::from_millis(1500)) });
+        assert_eq!(config.client.max_per_host, 4);
+        assert_eq!(config.client.ca_bundle, Some(PathBuf::from("/etc/weather/ca.pem")));
+        assert_eq!(config.client.retries.policy("owm").max_attempts, 2);
+        assert_eq!(config.client.retries.policy("apixu"), RetryPolicy {
+            max_attempts: 4,
+            backoff: Duration::from_millis(500),
+            ..RetryPolicy::default()
+        });
+        assert_eq!(config.client.retries.providers.len(), 1);
+        assert_eq!(config.breaker.open_for, Duration::from_secs(60));
+        assert_eq!(config.budget.limits("apixu"), Limits { per_minute: None, per_day: Some(500) });
+        assert_eq!(config.budget.limits("owm"), Limits { per_minute: Some(30), per_day: Some(500) });
+        assert_eq!(config.budget.limits("weatherbit"), Limits::default());
+        assert_eq!(config.budget.on_exhausted, Exhausted::ServeCache);
+        assert_eq!(config.keys, KeysConfig { rotation: Rotation::LeastUsed, ..KeysConfig::default() });
+
+        let invalid = Config::from_lookup(|name| if name == "CACHE_CAPACITY" { Some("many".to_owned()) } else { None });
+        assert_eq!(invalid.err(), Some("Invalid CACHE_CAPACITY: many".to_owned()));
+    }
+}
@@ -0,0 +1,421 @@
+extern crate serde_json;
+
+use std::fmt;
+use std::collections::BTreeMap;
+use self::serde_json::Value;
+
+use observation::most_common;
+use aggregation::{Aggregator, combine};
+use units::Units;
+use location::Coordinates;
+
+pub const FORECAST_DAYS: usize = 5;
+
+// Calendar date in the timezone of the forecast location
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ForecastDate {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32
+}
+
+impl ForecastDate {
+    pub fn new(year: i32, month: u32, day: u32) -> ForecastDate {
+        ForecastDate { year: year, month: month, day: day }
+    }
+
+    // Parses dates like "2018-02-03"
+    pub fn parse(s: &str) -> Option<ForecastDate> {
+        let parts: Vec<&str> = s.trim().splitn(3, '-').collect();
+        if parts.len() != 3 {
+            return None;
+        }
+
+        let year = parts[0].parse::<i32>().ok()?;
+        let month = parts[1].parse::<u32>().ok()?;
+        let day = parts[2].parse::<u32>().ok()?;
+        if month < 1 || month > 12 || day < 1 || day > 31 {
+            return None;
+        }
+
+        Some(ForecastDate::new(year, month, day))
+    }
+
+    // Local date of a unix timestamp at the given offset from UTC in seconds
+    pub fn from_timestamp(ts: i64, utc_offset: i64) -> ForecastDate {
+        let local = ts + utc_offset;
+        let days = if local >= 0 { local / 86400 } else { (local - 86399) / 86400 };
+        ForecastDate::from_days(days)
+    }
+
+    // Days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+    pub fn days(&self) -> i64 {
+        let y = if self.month <= 2 { self.year as i64 - 1 } else { self.year as i64 };
+        let era = if y >= 0 { y } else { y - 399 } / 400;
+        let yoe = y - era * 400;
+        let m = self.month as i64;
+        let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146097 + doe - 719468
+    }
+
+    pub fn from_days(days: i64) -> ForecastDate {
+        let z = days + 719468;
+        let era = if z >= 0 { z } else { z - 146096 } / 146097;
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        ForecastDate::new(year as i32, month as u32, day as u32)
+    }
+
+    pub fn add_days(&self, n: i64) -> ForecastDate {
+        ForecastDate::from_days(self.days() + n)
+    }
+}
+
+// Offset from UTC in seconds of a place whose local time "2018-02-03 20:12"
+// is the unix time `ts`, rounded to a quarter of an hour as seconds are left out
+pub fn utc_offset(local_time: &str, ts: i64) -> Option<i64> {
+    let mut parts = local_time.trim().splitn(2, ' ');
+    let date = parts.next().and_then(ForecastDate::parse)?;
+    let mut time = parts.next()?.splitn(2, ':');
+    let hours = time.next()?.trim().parse::<i64>().ok()?;
+    let minutes = time.next()?.trim().parse::<i64>().ok()?;
+
+    let local = date.days() * 86400 + hours * 3600 + minutes * 60;
+    let quarter = 15 * 60;
+    Some(((local - ts) as f64 / quarter as f64).round() as i64 * quarter)
+}
+
+impl fmt::Display for ForecastDate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+// Forecast for a single day, in the same units as `CurrentObservation`:
+// temperatures in °C, precipitation in mm, its probability in %,
+// wind speed in m/s (daily maximum for providers reporting only that)
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DailyForecast {
+    pub temperature: Option<f32>,
+    pub min_temperature: Option<f32>,
+    pub max_temperature: Option<f32>,
+    pub precipitation: Option<f32>,
+    pub precipitation_probability: Option<f32>,
+    pub wind_speed: Option<f32>,
+    pub condition: Option<String>
+}
+
+impl DailyForecast {
+    // Combines forecasts of several providers for the same day field by field
+    // with `aggregator`, returns None if there is nothing to combine
+    pub fn aggregate(forecasts: &[(&'static str, DailyForecast)], aggregator: &Aggregator) -> Option<DailyForecast> {
+        if forecasts.is_empty() {
+            return None;
+        }
+
+        let field = |f: &Fn(&DailyForecast) -> Option<f32>| combine(aggregator, forecasts.iter().map(|&(p, ref d)| (p, f(d))));
+
+        Some(DailyForecast {
+            temperature: field(&|d| d.temperature),
+            min_temperature: field(&|d| d.min_temperature),
+            max_temperature: field(&|d| d.max_temperature),
+            precipitation: field(&|d| d.precipitation),
+            precipitation_probability: field(&|d| d.precipitation_probability),
+            wind_speed: field(&|d| d.wind_speed),
+            condition: most_common(forecasts.iter().map(|&(_, ref d)| d.condition.clone()))
+        })
+    }
+
+    pub fn to_json(&self, date: &ForecastDate) -> Value {
+        json!({
+            "date": date.to_string(),
+            "temperature": self.temperature,
+            "min_temperature": self.min_temperature,
+            "max_temperature": self.max_temperature,
+            "precipitation": self.precipitation,
+            "precipitation_probability": self.precipitation_probability,
+            "wind_speed": self.wind_speed,
+            "condition": self.condition
+        })
+    }
+
+    // Converts metric values into `units`
+    pub fn convert(&self, units: &Units) -> DailyForecast {
+        DailyForecast {
+            temperature: self.temperature.map(|v| units.temperature.convert(v)),
+            min_temperature: self.min_temperature.map(|v| units.temperature.convert(v)),
+            max_temperature: self.max_temperature.map(|v| units.temperature.convert(v)),
+            precipitation: self.precipitation.map(|v| units.precipitation.convert(v)),
+            wind_speed: self.wind_speed.map(|v| units.wind_speed.convert(v)),
+            ..self.clone()
+        }
+    }
+
+    // Formats values already converted into `units`
+    pub fn format(&self, units: &Units) -> String {
+        let t = units.temperature.symbol();
+        let mut parts = vec![match self.temperature {
+            Some(v) => format!("temp = {:.1}{}", v, t),
+            None => format!("temp is unknown{}", t)
+        }];
+
+        match (self.min_temperature, self.max_temperature) {
+            (Some(min), Some(max)) => parts.push(format!("min {:.1}{}, max {:.1}{}", min, t, max, t)),
+            (Some(min), None) => parts.push(format!("min {:.1}{}", min, t)),
+            (None, Some(max)) => parts.push(format!("max {:.1}{}", max, t)),
+            _ => {}
+        }
+
+        let (precision, p_symbol) = (units.precipitation.precision(), units.precipitation.symbol());
+        match (self.precipitation, self.precipitation_probability) {
+            (Some(p), Some(pop)) => parts.push(format!("precipitation {:.*}{} ({:.0}%)", precision, p, p_symbol, pop)),
+            (Some(p), None) => parts.push(format!("precipitation {:.*}{}", precision, p, p_symbol)),
+            (None, Some(pop)) => parts.push(format!("precipitation {:.0}%", pop)),
+            _ => {}
+        }
+        if let Some(v) = self.wind_speed {
+            parts.push(format!("wind {:.1}{}", v, units.wind_speed.symbol()));
+        }
+        if let Some(ref v) = self.condition {
+            parts.push(v.clone());
+        }
+
+        parts.join(", ")
+    }
+}
+
+// Forecast of a single provider keyed by local calendar date,
+// coordinates are those of the place the provider reported for.
+// `utc_offset` is the location's offset from UTC in seconds, if the provider tells it
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Forecast {
+    pub timezone: Option<String>,
+    pub utc_offset: Option<i64>,
+    pub coordinates: Option<Coordinates>,
+    pub days: BTreeMap<ForecastDate, DailyForecast>
+}
+
+impl Forecast {
+    pub fn convert(&self, units: &Units) -> Forecast {
+        Forecast {
+            timezone: self.timezone.clone(),
+            utc_offset: self.utc_offset,
+            coordinates: self.coordinates,
+            days: self.days.iter().map(|(date, d)| (*date, d.convert(units))).collect()
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let days: Vec<Value> = self.days.iter().map(|(date, d)| d.to_json(date)).collect();
+        json!({
+            "timezone": self.timezone,
+            "days": days
+        })
+    }
+}
+
+// Forecast of several providers merged date by date,
+// dates no provider reported are kept as None
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergedForecast {
+    pub timezone: Option<String>,
+    pub days: Vec<(ForecastDate, Option<DailyForecast>)>
+}
+
+// Merges forecasts of several providers date by date for `days` days starting
+// with the location's today at unix time `now`. Today is the local date at the
+// offsets providers report for the location, the earliest of the most common ones.
+// When none of them reports an offset the latest first date among them is taken,
+// as providers never report days already past in the location
+pub fn merge(forecasts: Vec<(&'static str, Forecast)>, days: usize, aggregator: &Aggregator, now: i64) -> MergedForecast {
+    let timezone = forecasts.iter().filter_map(|&(_, ref f)| f.timezone.clone()).next();
+    let local_dates: Vec<ForecastDate> = forecasts.iter()
+        .filter_map(|&(_, ref f)| f.utc_offset.map(|offset| ForecastDate::from_timestamp(now, offset)))
+        .collect();
+    let today = local_dates.iter()
+        .max_by_key(|date| (local_dates.iter().filter(|d| d == date).count(), -date.days()))
+        .cloned()
+        .or_else(|| forecasts.iter().filter_map(|&(_, ref f)| f.days.keys().next()).max().cloned());
+
+    let days = match today {
+        Some(today) => (0..days as i64).map(|n| {
+            let date = today.add_days(n);
+            let values: Vec<(&'static str, DailyForecast)> = forecasts.iter()
+                .filter_map(|&(p, ref f)| f.days.get(&date).map(|d| (p, d.clone())))
+                .collect();
+            (date, DailyForecast::aggregate(&values, aggregator))
+        }).collect(),
+        None => vec![]
+    };
+
+    MergedForecast { timezone: timezone, days: days }
+}
+
+impl MergedForecast {
+    pub fn convert(&self, units: &Units) -> MergedForecast {
+        MergedForecast {
+            timezone: self.timezone.clone(),
+            days: self.days.iter().map(|&(date, ref d)| (date, d.as_ref().map(|d| d.convert(units)))).collect()
+        }
+    }
+
+    // Dates without forecast are reported with the `missing` flag
+    pub fn to_json(&self) -> Value {
+        let days: Vec<Value> = self.days.iter().map(|&(ref date, ref day)| match *day {
+            Some(ref d) => d.to_json(date),
+            None => json!({ "date": date.to_string(), "missing": true })
+        }).collect();
+        json!({
+            "timezone": self.timezone,
+            "days": days
+        })
+    }
+
+    pub fn format(&self, units: &Units) -> String {
+        let mut lines: Vec<String> = vec![];
+
+        if let Some(ref tz) = self.timezone {
+            lines.push(format!("timezone: {}", tz));
+        }
+
+        for (idx, &(ref date, ref day)) in self.days.iter().enumerate() {
+            lines.push(match *day {
+                Some(ref d) => format!("day{} {}: {}", idx+1, date, d.format(units)),
+                None => format!("day{} {}: no forecast", idx+1, date)
+            });
+        }
+
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aggregation::Mean;
+
+    fn day(temp: f32, min: f32, max: f32) -> DailyForecast {
+        DailyForecast {
+            temperature: Some(temp),
+            min_temperature: Some(min),
+            max_temperature: Some(max),
+            ..Default::default()
+        }
+    }
+
+    fn forecast(timezone: &str, days: Vec<(ForecastDate, DailyForecast)>) -> Forecast {
+        Forecast {
+            timezone: Some(timezone.to_owned()),
+            utc_offset: None,
+            coordinates: None,
+            days: days.into_iter().collect()
+        }
+    }
+
+    #[test]
+    fn dates_convert_to_and_from_days() {
+        let date = ForecastDate::parse("2018-02-03").unwrap();
+
+        assert_eq!(date, ForecastDate::new(2018, 2, 3));
+        assert_eq!(date.days(), 17565);
+        assert_eq!(ForecastDate::from_days(17565), date);
+        assert_eq!(ForecastDate::new(2016, 2, 28).add_days(1), ForecastDate::new(2016, 2, 29));
+        assert_eq!(ForecastDate::new(2017, 12, 31).add_days(1), ForecastDate::new(2018, 1, 1));
+        assert_eq!(date.to_string(), "2018-02-03");
+        assert_eq!(ForecastDate::parse("2018-02"), None);
+    }
+
+    #[test]
+    fn timestamps_map_to_local_dates() {
+        // 2018-02-03 21:00 UTC
+        let ts = 1517691600;
+
+        assert_eq!(ForecastDate::from_timestamp(ts, 0), ForecastDate::new(2018, 2, 3));
+        assert_eq!(ForecastDate::from_timestamp(ts, 5 * 3600), ForecastDate::new(2018, 2, 4));
+        assert_eq!(ForecastDate::from_timestamp(ts, -22 * 3600), ForecastDate::new(2018, 2, 2));
+    }
+
+    #[test]
+    fn merge_aligns_days_by_date() {
+        let d = |n| ForecastDate::new(2018, 2, n);
+        let a = forecast("Asia/Yekaterinburg", vec![(d(2), day(-20.0, -22.0, -18.0)), (d(3), day(-10.0, -14.0, -6.0)), (d(4), day(-6.0, -8.0, -4.0))]);
+        let b = forecast("Asia/Yekaterinburg", vec![(d(3), day(-8.0, -12.0, -4.0)), (d(5), day(-2.0, -4.0, 0.0))]);
+
+        let merged = merge(vec![("owm", a), ("apixu", b)], 4, &Mean, 1517670000);
+
+        assert_eq!(merged, MergedForecast {
+            timezone: Some("Asia/Yekaterinburg".to_owned()),
+            days: vec![
+                (d(3), Some(day(-9.0, -13.0, -5.0))),
+                (d(4), Some(day(-6.0, -8.0, -4.0))),
+                (d(5), Some(day(-2.0, -4.0, 0.0))),
+                (d(6), None)
+            ]
+        });
+    }
+
+    #[test]
+    fn merge_starts_with_local_today() {
+        let d = |n| ForecastDate::new(2018, 2, n);
+        // 2018-02-03 21:00 UTC is already the 4th in Yekaterinburg, but not yet in London
+        let now = 1517691600;
+        let yekaterinburg = Forecast { utc_offset: Some(5 * 3600), ..forecast("Asia/Yekaterinburg", vec![(d(4), day(-6.0, -8.0, -4.0)), (d(5), day(-2.0, -4.0, 0.0))]) };
+        let rolled_over = forecast("Asia/Yekaterinburg", vec![(d(5), day(-4.0, -6.0, -2.0))]);
+
+        let merged = merge(vec![("owm", yekaterinburg.clone()), ("weatherbit", rolled_over)], 2, &Mean, now);
+        assert_eq!(merged.days, vec![(d(4), Some(day(-6.0, -8.0, -4.0))), (d(5), Some(day(-3.0, -5.0, -1.0)))]);
+
+        let london = Forecast { utc_offset: Some(0), ..forecast("Europe/London", vec![(d(3), day(1.0, 0.0, 2.0))]) };
+        let merged = merge(vec![("owm", yekaterinburg.clone()), ("apixu", yekaterinburg), ("weatherbit", london)], 1, &Mean, now);
+        assert_eq!(merged.days[0].0, d(4));
+    }
+
+    #[test]
+    fn local_time_gives_utc_offset() {
+        assert_eq!(utc_offset("2018-02-03 20:12", 1517670734), Some(5 * 3600));
+        assert_eq!(utc_offset("2018-02-03 3:30", 1517616000), Some(3 * 3600 + 30 * 60));
+        assert_eq!(utc_offset("2018-02-02 19:00", 1517616045), Some(-5 * 3600));
+        assert_eq!(utc_offset("2018-02-03", 1517670734), None);
+    }
+
+    #[test]
+    fn to_json_reports_missing_days() {
+        let merged = MergedForecast {
+            timezone: None,
+            days: vec![(ForecastDate::new(2018, 2, 3), Some(day(-9.8, -13.0, -5.7))), (ForecastDate::new(2018, 2, 4), None)]
+        };
+
+        let json = merged.to_json();
+
+        assert!(json["timezone"].is_null());
+        assert_eq!(json["days"][0]["date"], "2018-02-03");
+        assert_eq!(json["days"][0]["max_temperature"], -5.7f32 as f64);
+        assert_eq!(json["days"][1], json!({ "date": "2018-02-04", "missing": true }));
+    }
+
+    #[test]
+    fn format_reports_missing_days() {
+        let mut d = day(-9.8, -13.0, -5.7);
+        d.precipitation = Some(0.2);
+        d.condition = Some("Light snow".to_owned());
+
+        let merged = MergedForecast {
+            timezone: Some("Asia/Yekaterinburg".to_owned()),
+            days: vec![(ForecastDate::new(2018, 2, 3), Some(d)), (ForecastDate::new(2018, 2, 4), None)]
+        };
+
+        assert_eq!(
+            merged.format(&Units::metric()),
+            "timezone: Asia/Yekaterinburg\n\
+             day1 2018-02-03: temp = -9.8°C, min -13.0°C, max -5.7°C, precipitation 0.2mm, Light snow\n\
+             day2 2018-02-04: no forecast"
+        );
+    }
+}
@@ -0,0 +1,174 @@
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+extern crate serde_json;
+
+use std::fmt;
+use std::rc::Rc;
+
+use self::futures::Future;
+use self::serde_json::Value;
+
+use async_request::ApiFuture;
+use async_request::client::HttpClient;
+use async_request::error::ApiError;
+use provider::ProviderResult;
+use location::{Location, Coordinates};
+use apixu::Apixu;
+use keys::KeyRings;
+
+// Providers reporting weather farther than this from the resolved place are rejected
+pub const MAX_DISTANCE_KM: f64 = 50.0;
+
+// Place the query resolved to, every provider is asked about its coordinates
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanonicalLocation {
+    pub name: String,
+    pub region: Option<String>,
+    pub country: Option<String>,
+    pub coordinates: Coordinates,
+    pub timezone: Option<String>
+}
+
+impl CanonicalLocation {
+    // Location the providers are queried with
+    pub fn location(&self) -> Location {
+        Location::Coordinates { lat: self.coordinates.lat, lon: self.coordinates.lon }
+    }
+
+    // Whether coordinates reported by a provider are close enough,
+    // providers which don't report them are trusted
+    pub fn is_near(&self, reported: Option<Coordinates>) -> bool {
+        match reported {
+            Some(point) => self.coordinates.distance_km(&point) <= MAX_DISTANCE_KM,
+            None => true
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "region": self.region,
+            "country": self.country,
+            "lat": self.coordinates.lat,
+            "lon": self.coordinates.lon,
+            "timezone": self.timezone
+        })
+    }
+}
+
+impl fmt::Display for CanonicalLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parts: Vec<&str> = Some(self.name.as_str()).into_iter()
+            .chain(self.region.as_ref().map(|s| s.as_str()))
+            .chain(self.country.as_ref().map(|s| s.as_str()))
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        write!(f, "{} ({:.2}, {:.2})", parts.join(", "), self.coordinates.lat, self.coordinates.lon)
+    }
+}
+
+pub trait Geocoder {
+    fn resolve(&self, client: &HttpClient, location: &Location) -> ApiFuture<CanonicalLocation>;
+}
+
+// Geocoder the server resolves queries with
+pub fn geocoder(keys: &KeyRings) -> Rc<Geocoder> {
+    Rc::new(Apixu::new(keys.apixu.clone()))
+}
+
+// Resolves the query once for all providers, requested coordinates
+// are kept as is instead of the geocoder's rounded ones
+pub fn resolve(geocoder: &Geocoder, client: &HttpClient, location: &Location) -> ApiFuture<CanonicalLocation> {
+    let requested = match *location {
+        Location::Coordinates { lat, lon } => Some(Coordinates::new(lat, lon)),
+        _ => None
+    };
+
+    Box::new(geocoder.resolve(client, location).map(move |result| result.map(|canonical| match requested {
+        Some(point) => CanonicalLocation { coordinates: point, ..canonical },
+        None => canonical
+    })))
+}
+
+// Replaces results reported too far from the canonical place with errors
+pub fn reject_distant<T, F: Fn(&T) -> Option<Coordinates>>(results: Vec<ProviderResult<T>>, canonical: &CanonicalLocation, reported: F) -> Vec<ProviderResult<T>> {
+    results.into_iter().map(|r| {
+        let near = match r.result {
+            Ok(ref v) => canonical.is_near(reported(v)),
+            Err(_) => true
+        };
+
+        if near {
+            r
+        } else {
+            ProviderResult { result: Err(ApiError::LocationMismatch), ..r }
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn perm() -> CanonicalLocation {
+        CanonicalLocation {
+            name: "Perm".to_owned(),
+            region: Some("Perm'".to_owned()),
+            country: Some("Russia".to_owned()),
+            coordinates: Coordinates::new(58.0, 56.25),
+            timezone: Some("Asia/Yekaterinburg".to_owned())
+        }
+    }
+
+    struct FixedGeocoder;
+
+    impl Geocoder for FixedGeocoder {
+        fn resolve(&self, _client: &HttpClient, _location: &Location) -> ApiFuture<CanonicalLocation> {
+            Box::new(futures::future::ok(Ok(perm())))
+        }
+    }
+
+    #[test]
+    fn it_keeps_requested_coordinates() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+
+        let by_name = core.run(resolve(&FixedGeocoder, &client, &Location::Name("perm".to_owned()))).unwrap().unwrap();
+        assert_eq!(by_name, perm());
+
+        let by_coordinates = core.run(resolve(&FixedGeocoder, &client, &Location::Coordinates { lat: 58.01, lon: 56.23 })).unwrap().unwrap();
+        assert_eq!(by_coordinates.coordinates, Coordinates::new(58.01, 56.23));
+        assert_eq!(by_coordinates.location(), Location::Coordinates { lat: 58.01, lon: 56.23 });
+        assert_eq!(by_coordinates.name, "Perm");
+    }
+
+    #[test]
+    fn it_rejects_distant_results() {
+        let results = vec![
+            ProviderResult { name: "owm", result: Ok(Some(Coordinates::new(58.02, 56.3))), latency: Duration::from_millis(100), age: None },
+            ProviderResult { name: "apixu", result: Ok(None), latency: Duration::from_millis(100), age: None },
+            // Perm, Oklahoma
+            ProviderResult { name: "weatherbit", result: Ok(Some(Coordinates::new(36.4, -97.38))), latency: Duration::from_millis(100), age: None }
+        ];
+
+        let checked: Vec<_> = reject_distant(results, &perm(), |c| *c).into_iter().map(|r| (r.name, r.result)).collect();
+
+        assert_eq!(checked, vec![
+            ("owm", Ok(Some(Coordinates::new(58.02, 56.3)))),
+            ("apixu", Ok(None)),
+            ("weatherbit", Err(ApiError::LocationMismatch))
+        ]);
+    }
+
+    #[test]
+    fn it_formats_canonical_location() {
+        assert_eq!(perm().to_string(), "Perm, Perm', Russia (58.00, 56.25)");
+        assert_eq!(perm().to_json(), json!({
+            "name": "Perm", "region": "Perm'", "country": "Russia",
+            "lat": 58.0, "lon": 56.25, "timezone": "Asia/Yekaterinburg"
+        }));
+    }
+}
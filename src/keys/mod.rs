@@ -0,0 +1,342 @@
+// Spreads provider calls over several api keys. Keys the provider rejects
+// or which ran out of quota are set aside for a cool-down
+
+extern crate futures;
+extern crate tokio_core;
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use self::futures::Future;
+
+use async_request::ApiFuture;
+use async_request::client::HttpClient;
+use async_request::error::ApiError;
+use budget::{self, Budgets};
+use ApiKeys;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    RoundRobin,
+    // key with the fewest calls since startup
+    LeastUsed
+}
+
+impl Rotation {
+    pub fn parse(s: &str) -> Option<Rotation> {
+        match s.to_lowercase().as_str() {
+            "round_robin" | "round-robin" => Some(Rotation::RoundRobin),
+            "least_used" | "least-used" => Some(Rotation::LeastUsed),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeysConfig {
+    pub rotation: Rotation,
+    pub cool_down: Duration
+}
+
+impl Default for KeysConfig {
+    fn default() -> KeysConfig {
+        KeysConfig { rotation: Rotation::RoundRobin, cool_down: Duration::from_secs(60 * 60) }
+    }
+}
+
+// Keys given as "key1,key2", blank ones are skipped
+pub fn parse_keys(s: &str) -> Vec<String> {
+    s.split(',').map(|key| key.trim()).filter(|key| !key.is_empty()).map(|key| key.to_owned()).collect()
+}
+
+// Names a key without revealing it, budgets of keys are saved under it:
+// the FNV-1a hash of the key
+pub fn key_id(key: &str) -> String {
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, b| (hash ^ b as u64).wrapping_mul(0x100000001b3));
+    format!("{:016x}", hash)
+}
+
+#[derive(Debug)]
+struct Key {
+    value: String,
+    uses: u64,
+    // until when the key is set aside and why
+    disabled: Option<(Instant, ApiError)>
+}
+
+// Keys of a provider, clones share them. Calls made with a key are
+// taken out of the key's budget when the ring has budgets
+#[derive(Clone)]
+pub struct KeyRing {
+    config: KeysConfig,
+    keys: Rc<RefCell<Vec<Key>>>,
+    next: Rc<Cell<usize>>,
+    budgets: Option<(&'static str, Budgets)>
+}
+
+impl KeyRing {
+    pub fn new(keys: &[String], config: KeysConfig) -> KeyRing {
+        KeyRing {
+            config: config,
+            keys: Rc::new(RefCell::new(keys.iter().map(|k| Key { value: k.clone(), uses: 0, disabled: None }).collect())),
+            next: Rc::new(Cell::new(0)),
+            budgets: None
+        }
+    }
+
+    // Ring taking every upstream attempt made with a key out of the key's budget
+    pub fn with_budgets(&self, provider: &'static str, budgets: &Budgets) -> KeyRing {
+        KeyRing { budgets: Some((provider, budgets.clone())), ..self.clone() }
+    }
+
+    // Key for the next call. When every key is set aside, the error
+    // the soonest returning one was disabled with
+    pub fn pick(&self, now: Instant) -> Result<String, ApiError> {
+        self.pick_untried(now, &[])
+    }
+
+    // Key for the next call other than the `tried` ones
+    fn pick_untried(&self, now: Instant, tried: &[String]) -> Result<String, ApiError> {
+        let mut keys = self.keys.borrow_mut();
+        for key in keys.iter_mut() {
+            if key.disabled.as_ref().map(|&(until, _)| until <= now).unwrap_or(false) {
+                key.disabled = None;
+            }
+        }
+
+        let count = keys.len();
+        let active = |i: &usize| keys[*i].disabled.is_none() && !tried.contains(&keys[*i].value);
+        let picked = match self.config.rotation {
+            Rotation::RoundRobin => (0..count).map(|offset| (self.next.get() + offset) % count).find(active),
+            Rotation::LeastUsed => (0..count).filter(active).min_by_key(|i| keys[*i].uses)
+        };
+
+        match picked {
+            Some(i) => {
+                self.next.set(i + 1);
+                keys[i].uses += 1;
+                Ok(keys[i].value.clone())
+            },
+            None => Err(keys.iter()
+                .filter_map(|key| key.disabled.as_ref())
+                .min_by_key(|&&(until, _)| until)
+                .map(|&(_, ref e)| e.clone())
+                .unwrap_or(ApiError::InvalidApiKey))
+        }
+    }
+
+    // Sets the key aside if the call failed because of it: for the cool-down when the
+    // provider rejected it, until the UTC day rolls over when its daily budget is used up
+    pub fn report(&self, key: &str, error: &ApiError, now: Instant) {
+        let until = match *error {
+            ApiError::InvalidApiKey | ApiError::QuotaExceeded => now + self.config.cool_down,
+            ApiError::BudgetExhausted => now + budget::rest_of_day(),
+            _ => return
+        };
+
+        if let Some(k) = self.keys.borrow_mut().iter_mut().find(|k| k.value == key) {
+            k.disabled = Some((until, error.clone()));
+        }
+    }
+
+    pub fn active(&self, now: Instant) -> usize {
+        self.keys.borrow().iter().filter(|k| k.disabled.as_ref().map(|&(until, _)| until <= now).unwrap_or(true)).count()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Makes the call with the next key and a client metered by the key's budget.
+    // Calls failing because of the key are repeated with the next active one
+    // until no keys are left, rate limited ones with the next key not tried yet
+    pub fn call<T, F>(&self, client: &HttpClient, request: F) -> ApiFuture<T>
+        where T: 'static, F: Fn(&HttpClient, &str) -> ApiFuture<T> + 'static {
+        self.call_untried(client.clone(), request, Vec::new(), None)
+    }
+
+    fn call_untried<T, F>(&self, client: HttpClient, request: F, mut tried: Vec<String>, last: Option<ApiError>) -> ApiFuture<T>
+        where T: 'static, F: Fn(&HttpClient, &str) -> ApiFuture<T> + 'static {
+        let key = match self.pick_untried(Instant::now(), &tried) {
+            Ok(key) => key,
+            Err(e) => return Box::new(futures::future::ok(Err(last.unwrap_or(e))))
+        };
+        let metered = match self.budgets {
+            Some((provider, ref budgets)) => client.with_meter(budgets.meter(provider, &key_id(&key))),
+            None => client.clone()
+        };
+
+        let ring = self.clone();
+        Box::new(request(&metered, &key).and_then(move |result| -> ApiFuture<T> {
+            let error = match result {
+                Err(e) => e,
+                result => return Box::new(futures::future::ok(result))
+            };
+            match error {
+                ref e if Self::key_failure(e) => ring.report(&key, e, Instant::now()),
+                ApiError::RateLimited(_) => {},
+                e => return Box::new(futures::future::ok(Err(e)))
+            }
+            tried.push(key);
+            ring.call_untried(client, request, tried, Some(error))
+        }))
+    }
+
+    fn key_failure(error: &ApiError) -> bool {
+        match *error {
+            ApiError::InvalidApiKey | ApiError::QuotaExceeded | ApiError::BudgetExhausted => true,
+            _ => false
+        }
+    }
+}
+
+// Key rings of every provider, shared by all connections
+#[derive(Clone)]
+pub struct KeyRings {
+    pub owm: KeyRing,
+    pub apixu: KeyRing,
+    pub weatherbit: KeyRing
+}
+
+impl KeyRings {
+    pub fn new(keys: &ApiKeys, config: KeysConfig, budgets: &Budgets) -> KeyRings {
+        KeyRings {
+            owm: KeyRing::new(&keys.owm_keys, config).with_budgets("owm", budgets),
+            apixu: KeyRing::new(&keys.apixu_keys, config).with_budgets("apixu", budgets),
+            weatherbit: KeyRing::new(&keys.weatherbit_keys, config).with_budgets("weatherbit", budgets)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::futures::future;
+
+    fn ring(rotation: Rotation) -> KeyRing {
+        KeyRing::new(&parse_keys("first, second,,third"), KeysConfig { rotation: rotation, cool_down: Duration::from_secs(60) })
+    }
+
+    #[test]
+    fn it_rotates_keys() {
+        let now = Instant::now();
+        let round_robin = ring(Rotation::RoundRobin);
+        let picked: Vec<String> = (0..4).map(|_| round_robin.pick(now).unwrap()).collect();
+        assert_eq!(picked, vec!["first", "second", "third", "first"]);
+
+        let least_used = ring(Rotation::LeastUsed);
+        least_used.keys.borrow_mut()[0].uses = 5;
+        least_used.keys.borrow_mut()[1].uses = 2;
+        let picked: Vec<String> = (0..3).map(|_| least_used.pick(now).unwrap()).collect();
+        assert_eq!(picked, vec!["third", "third", "second"]);
+    }
+
+    #[test]
+    fn failing_keys_cool_down() {
+        let now = Instant::now();
+        let keys = ring(Rotation::RoundRobin);
+
+        keys.report("first", &ApiError::InvalidApiKey, now);
+        keys.report("second", &ApiError::Timeout, now);
+        keys.report("third", &ApiError::QuotaExceeded, now + Duration::from_secs(1));
+        assert_eq!(keys.active(now), 1);
+        assert_eq!(keys.pick(now).unwrap(), "second");
+
+        keys.report("second", &ApiError::QuotaExceeded, now);
+        assert_eq!(keys.pick(now), Err(ApiError::InvalidApiKey));
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(keys.pick(later).unwrap(), "first");
+        assert_eq!(keys.active(later), 2);
+
+        assert_eq!(KeyRing::new(&[], KeysConfig::default()).pick(now), Err(ApiError::InvalidApiKey));
+    }
+
+    #[test]
+    fn calls_move_on_to_next_key() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let keys = ring(Rotation::RoundRobin);
+
+        let result = core.run(keys.call(&client, |_, key| -> ApiFuture<String> {
+            match key {
+                "first" => Box::new(future::ok(Err(ApiError::QuotaExceeded))),
+                "second" => Box::new(future::ok(Err(ApiError::InvalidApiKey))),
+                key => Box::new(future::ok(Ok(key.to_owned())))
+            }
+        })).unwrap();
+
+        assert_eq!(result, Ok("third".to_owned()));
+        assert_eq!(keys.active(Instant::now()), 1);
+
+        let exhausted = core.run(keys.call(&client, |_, _| -> ApiFuture<String> { Box::new(future::ok(Err(ApiError::QuotaExceeded))) })).unwrap();
+        assert_eq!(exhausted, Err(ApiError::QuotaExceeded));
+        assert_eq!(keys.active(Instant::now()), 0);
+    }
+
+    #[test]
+    fn rate_limited_calls_try_every_key_once() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let keys = ring(Rotation::RoundRobin);
+        let tried = Rc::new(RefCell::new(Vec::new()));
+
+        let calls = tried.clone();
+        let result = core.run(keys.call(&client, move |_, key| -> ApiFuture<String> {
+            calls.borrow_mut().push(key.to_owned());
+            Box::new(future::ok(Err(ApiError::RateLimited(None))))
+        })).unwrap();
+
+        assert_eq!(result, Err(ApiError::RateLimited(None)));
+        assert_eq!(*tried.borrow(), vec!["first", "second", "third"]);
+        // rate limited keys aren't set aside
+        assert_eq!(keys.active(Instant::now()), 3);
+    }
+
+    #[test]
+    fn calls_are_taken_out_of_key_budgets() {
+        use budget::{BudgetConfig, Limits};
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let budgets = Budgets::new(&core.handle(), BudgetConfig { default: Limits { per_minute: None, per_day: Some(1) }, ..BudgetConfig::default() });
+        let keys = ring(Rotation::RoundRobin).with_budgets("owm", &budgets);
+        let metered = |client: &HttpClient, key: &str| -> ApiFuture<String> {
+            let key = key.to_owned();
+            Box::new(client.allowance().map(move |allowed| allowed.map(|_| key)))
+        };
+
+        let used: Vec<_> = (0..4).map(|_| core.run(keys.call(&client, metered)).unwrap()).collect();
+
+        assert_eq!(used, vec![Ok("first".to_owned()), Ok("second".to_owned()), Ok("third".to_owned()), Err(ApiError::BudgetExhausted)]);
+        assert_eq!(budgets.calls_today("owm"), 3);
+        // keys out of budget are set aside until the day rolls over, not for the cool-down
+        assert_eq!(keys.active(Instant::now() + Duration::from_secs(120)), 0);
+        assert_eq!(keys.pick(Instant::now() + budget::rest_of_day() + Duration::from_secs(1)).map(|_| ()), Ok(()));
+        let saved: Vec<_> = budgets.records().iter().map(|r| r.value["key"].as_str().unwrap().to_owned()).collect();
+        assert_eq!(saved.len(), 3);
+        assert!(saved.contains(&key_id("first")));
+    }
+
+    #[test]
+    fn every_provider_needs_keys() {
+        let lookup = |blank: &'static str| move |name: &str| Some(if name == blank { " , ".to_owned() } else { "a,b".to_owned() });
+
+        assert!(ApiKeys::from_lookup(lookup("none")).is_ok());
+        assert_eq!(ApiKeys::from_lookup(lookup("APIXU_KEY")).err(), Some("APIXU_KEY lists no keys".to_owned()));
+        assert_eq!(ApiKeys::from_lookup(|_| None).err(), Some("OWM_KEY is absent".to_owned()));
+    }
+
+    #[test]
+    fn key_ids_hide_keys() {
+        assert_eq!(key_id(""), "cbf29ce484222325");
+        assert_eq!(key_id("first"), key_id("first"));
+        assert!(key_id("first") != key_id("second"));
+        assert!(!key_id("first").contains("first"));
+    }
+}
@@ -1,34 +1,83 @@
 extern crate futures;
 extern crate hyper;
 extern crate tokio_core;
+#[macro_use]
 extern crate serde_json;
 
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use futures::{Future, Stream};
 use hyper::{Get, StatusCode};
 use hyper::error::Error;
-use tokio_core::reactor::Handle;
-use hyper::header::{ContentLength, ContentType};
+use hyper::mime;
+use tokio_core::reactor::Timeout;
+use hyper::header::{Accept, CacheControl, CacheDirective, ContentLength, ContentType, RetryAfter};
 use hyper::server::{Http, Service, Request, Response};
+use serde_json::Value;
 
 use async_request::error::ApiError;
+use async_request::client::HttpClient;
+use provider::{Providers, ProviderResult, registry, format_breakdown};
+use observation::CurrentObservation;
+use forecast::Forecast;
+use query::{Query, Format};
+use location::Location;
+use geocoding::{Geocoder, CanonicalLocation, geocoder, reject_distant};
+use cache::{Cache, ResponseCache, CacheKey};
+use config::Config;
+use aggregation::{Aggregator, Sample, Spread, aggregator};
+use quorum::{QuorumConfig, gather};
+use breaker::{Breakers, State};
+use budget::{Budgets, Exhausted};
+use keys::KeyRings;
+use async_request::ApiFuture;
 
 pub type ResponseStream = Box<Stream<Item = String, Error=Error>>;
 
 pub mod async_request;
+pub mod provider;
+pub mod observation;
+pub mod forecast;
+pub mod query;
+pub mod units;
+pub mod location;
+pub mod geocoding;
+pub mod config;
+pub mod cache;
+pub mod aggregation;
+pub mod quorum;
+pub mod breaker;
+pub mod budget;
+pub mod keys;
+pub mod store;
 mod owm;
 mod apixu;
 mod weatherbit;
 
-pub fn start_server(address: &str, keys: ApiKeys) -> tokio_core::reactor::Core {
+pub fn start_server(address: &str, keys: ApiKeys, config: Config) -> tokio_core::reactor::Core {
     let addr = address.parse().unwrap();
 
     let core = tokio_core::reactor::Core::new().unwrap();
     let handle = core.handle();
-    let client_handle = core.handle();
+    // shared by all connections
+    let client = HttpClient::with_config(&handle, &config.client).unwrap();
+    let cache = ResponseCache::new(&config.cache);
+    store::persist(&handle, &cache, &config.cache);
+    let breakers = Breakers::new(config.breaker);
+    let budgets = Budgets::new(&handle, config.budget.clone());
+    let keys = KeyRings::new(&keys, config.keys, &budgets);
+    budget::persist(&handle, &budgets);
+    let config = Rc::new(config);
 
     let serve = Http::new().serve_addr_handle(&addr, &handle, move || Ok(WeatherServer::new(
-        client_handle.clone(),
-        keys.clone()
+        client.clone(),
+        keys.clone(),
+        cache.clone(),
+        breakers.clone(),
+        budgets.clone(),
+        config.clone()
     ))).unwrap();
     println!("Listening on http://{} with 1 thread.", serve.incoming_ref().local_addr());
 
@@ -43,54 +92,369 @@ pub fn start_server(address: &str, keys: ApiKeys) -> tokio_core::reactor::Core {
 
 
 pub struct WeatherServer {
-    handle: Handle,
-    keys: ApiKeys
+    client: HttpClient,
+    providers: Rc<Providers>,
+    geocoder: Rc<Geocoder>,
+    cache: ResponseCache,
+    guards: Guards,
+    config: Rc<Config>
+}
+
+// Per-provider state every provider call goes through, shared by all connections
+#[derive(Clone)]
+struct Guards {
+    breakers: Breakers,
+    budgets: Budgets,
+    // how old responses served instead of calls over budget may be
+    max_stale: Duration
+}
+
+impl Guards {
+    // Provider call answered from the cache when possible, otherwise made unless
+    // its breaker is open. With `Exhausted::ServeCache` calls over the budgets
+    // of the provider's keys are answered with its last response instead
+    fn fetch<T, F>(&self, cache: &Cache<T>, provider: &'static str, location: &Location, request: F) -> Box<Future<Item = ProviderResult<T>, Error = hyper::Error>>
+        where T: Clone + 'static, F: FnOnce() -> ApiFuture<T> + 'static {
+        let fetched = cache.fetch(provider, location, || self.breakers.call(provider, request));
+        if self.budgets.on_exhausted() != Exhausted::ServeCache {
+            return fetched;
+        }
+
+        let cache = cache.clone();
+        let key = CacheKey::new(provider, location);
+        let max_stale = self.max_stale;
+        Box::new(fetched.map(move |r| match r.result {
+            Err(ApiError::RateLimited(_)) | Err(ApiError::QuotaExceeded) | Err(ApiError::BudgetExhausted) => match cache.get_within(&key, max_stale) {
+                Some((value, age)) => ProviderResult { result: Ok(value), age: Some(age), ..r },
+                None => r
+            },
+            _ => r
+        }))
+    }
 }
 
+// Api keys of every provider, calls are spread over them
 #[derive(Debug, Clone)]
 pub struct ApiKeys {
-    owm_key: String,
-    apixu_key: String,
-    weatherbit_key: String
+    owm_keys: Vec<String>,
+    apixu_keys: Vec<String>,
+    weatherbit_keys: Vec<String>
 }
 
 impl ApiKeys {
-    pub fn new(owm_key: String, apixu_key: String, weatherbit_key: String) -> ApiKeys {
+    pub fn new(owm_keys: Vec<String>, apixu_keys: Vec<String>, weatherbit_keys: Vec<String>) -> ApiKeys {
         ApiKeys {
-            owm_key: owm_key,
-            apixu_key: apixu_key,
-            weatherbit_key: weatherbit_key
+            owm_keys: owm_keys,
+            apixu_keys: apixu_keys,
+            weatherbit_keys: weatherbit_keys
         }
     }
+
+    pub fn from_env() -> Result<ApiKeys, String> {
+        ApiKeys::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    // OWM_KEY, APIXU_KEY and WEATHERBIT_KEY may list several keys separated
+    // by commas, every provider needs at least one
+    pub fn from_lookup<F: Fn(&str) -> Option<String>>(lookup: F) -> Result<ApiKeys, String> {
+        let keys = |name: &str| match lookup(name).map(|v| keys::parse_keys(&v)) {
+            Some(keys) => if keys.is_empty() { Err(format!("{} lists no keys", name)) } else { Ok(keys) },
+            None => Err(format!("{} is absent", name))
+        };
+
+        Ok(ApiKeys::new(keys("OWM_KEY")?, keys("APIXU_KEY")?, keys("WEATHERBIT_KEY")?))
+    }
 }
 
 impl WeatherServer {
-    pub fn new(handle: Handle, keys: ApiKeys) -> WeatherServer {
+    pub fn new(client: HttpClient, keys: KeyRings, cache: ResponseCache, breakers: Breakers, budgets: Budgets, config: Rc<Config>) -> WeatherServer {
         WeatherServer {
-            handle: handle,
-            keys: keys,
+            client: client,
+            providers: Rc::new(registry(&keys)),
+            geocoder: geocoder(&keys),
+            cache: cache,
+            guards: Guards { breakers: breakers, budgets: budgets, max_stale: config.cache.max_stale },
+            config: config,
         }
     }
 
-    fn empty_query_body() -> <WeatherServer as Service>::Future {
-        let body = "Provide location as query";
-        let resp = Response::new()
-                    .with_status(StatusCode::UnprocessableEntity)
-                    .with_header(ContentLength(body.len() as u64))
-                    .with_header(ContentType::plaintext())
-                    .with_body(body);
+    fn empty_query_body(format: Format) -> <WeatherServer as Service>::Future {
+        let resp = Self::error_response(StatusCode::UnprocessableEntity, "Provide location as query", format);
+        Box::new(futures::future::ok(resp))
+    }
+
+    fn invalid_query_body(message: &str, format: Format) -> <WeatherServer as Service>::Future {
+        let resp = Self::error_response(StatusCode::UnprocessableEntity, message, format);
         Box::new(futures::future::ok(resp))
     }
 
-    fn format_temps(vec: Vec<Option<f32>>) -> String {
-        let new_vec: Vec<String> = vec.into_iter().enumerate().map(|(idx, val)| match val {
-            Some(t) => format!("day{} temp = {:.1}°C", idx+1, t),
-            None => format!("day{} temp is unknown°C", idx+1)
-        }).collect();
-        new_vec.join("\n")
+    // Format asked for in the query or in Accept header, plaintext by default
+    fn response_format(req: &Request, requested: Option<Format>) -> Format {
+        if let Some(format) = requested {
+            return format;
+        }
+
+        match req.headers().get::<Accept>() {
+            Some(accept) if accept.iter().any(|q| q.item.type_() == mime::APPLICATION && q.item.subtype() == mime::JSON) => Format::Json,
+            _ => Format::Text
+        }
+    }
+
+    // Location asked for in the query with "auto" replaced by the client's ip
+    fn request_location(req: &Request, query: &Query) -> Option<Result<Location, String>> {
+        query.location.clone().map(|l| l.resolve_auto(Self::client_ip(req)))
+    }
+
+    // First address in X-Forwarded-For set by the load balancer, or the peer address
+    fn client_ip(req: &Request) -> Option<IpAddr> {
+        let forwarded = req.headers().get_raw("X-Forwarded-For")
+            .and_then(|raw| raw.one())
+            .and_then(|line| std::str::from_utf8(line).ok())
+            .and_then(|line| line.split(',').next())
+            .and_then(|ip| ip.trim().parse::<IpAddr>().ok());
+
+        forwarded.or(req.remote_addr().map(|addr| addr.ip()))
+    }
+
+    // Resolves the location once for all providers. A location the geocoder
+    // doesn't know is answered with 404, if the geocoder itself fails
+    // providers are asked about the location as requested
+    fn resolve_location(&self, location: &Location, format: Format) -> Box<Future<Item = std::result::Result<Option<CanonicalLocation>, Response>, Error = hyper::Error>> {
+        let key = CacheKey::new("geocoder", location);
+        if let Some((canonical, _age)) = self.cache.locations.get(&key) {
+            return Box::new(futures::future::ok(Ok(Some(canonical))));
+        }
+
+        let locations = self.cache.locations.clone();
+        let resolution = geocoding::resolve(&*self.geocoder, &self.client, location);
+
+        Box::new(resolution.map(move |result| match result {
+            Ok(canonical) => {
+                locations.insert(key, canonical.clone());
+                Ok(Some(canonical))
+            },
+            Err(ApiError::LocationNotFound) => Err(Self::error_response(StatusCode::NotFound, "Location not found", format)),
+            Err(_) => Ok(None)
+        }))
+    }
+
+    fn location_line(canonical: &Option<CanonicalLocation>) -> String {
+        canonical.as_ref().map(|c| format!("location: {}\n", c)).unwrap_or_default()
+    }
+
+    fn location_json(canonical: &Option<CanonicalLocation>, location: &Location) -> Value {
+        canonical.as_ref().map(|c| c.to_json()).unwrap_or_else(|| location.to_json())
+    }
+
+    // Cache-Control and Age reflect the oldest provider response the body is built from
+    fn with_cache_headers<T>(mut resp: Response, results: &[ProviderResult<T>], ttl: Duration) -> Response {
+        let age = results.iter()
+            .filter(|r| r.result.is_ok())
+            .filter_map(|r| r.age)
+            .max()
+            .unwrap_or(Duration::from_secs(0));
+        let fresh_for = ttl.checked_sub(age).unwrap_or(Duration::from_secs(0));
+
+        resp.headers_mut().set(CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(fresh_for.as_secs() as u32)]));
+        resp.headers_mut().set_raw("Age", age.as_secs().to_string());
+        resp
+    }
+
+    // Stale responses are marked as such and shouldn't be reused downstream
+    fn with_stale_headers(mut resp: Response, age: Duration) -> Response {
+        resp.headers_mut().set(CacheControl(vec![CacheDirective::NoCache]));
+        resp.headers_mut().set_raw("Age", age.as_secs().to_string());
+        resp.headers_mut().set_raw("Warning", "110 - \"Response is Stale\"");
+        resp
+    }
+
+    // Current weather from every provider able to report it for the location,
+    // gathered according to the quorum policy, retries stop at its deadline.
+    // Providers with open circuit breakers or out of budget are skipped,
+    // responses reported far from the canonical place are rejected
+    fn fetch_current(providers: &Providers, client: &HttpClient, cache: &ResponseCache, guards: &Guards, quorum: &QuorumConfig, canonical: &Option<CanonicalLocation>, target: &Location) -> Box<Future<Item = Vec<ProviderResult<CurrentObservation>>, Error = hyper::Error>> {
+        let client = client.with_deadline(quorum.deadline.map(|d| Instant::now() + d));
+        let requests: Vec<_> = providers.iter()
+            .filter(|p| p.capabilities().current && p.supports(target))
+            .map(|p| {
+                let (provider, client, location) = (p.clone(), client.for_provider(p.name()), target.clone());
+                (p.name(), guards.fetch(&cache.current, p.name(), target, move || provider.current(&client, &location)))
+            })
+            .collect();
+        let canonical = canonical.clone();
+
+        Box::new(gather(client.handle(), requests, quorum).map(move |results| match canonical {
+            Some(ref c) => reject_distant(results, c, |o| o.coordinates),
+            None => results
+        }))
+    }
+
+    // Asks providers about the location again a bit later, so requests following
+    // a stale response get fresh data. Only one revalidation per location runs at a time,
+    // nobody waits for it so every provider is waited for
+    fn revalidate_current(client: &HttpClient, providers: Rc<Providers>, cache: ResponseCache, guards: Guards, aggregator: Box<Aggregator>, canonical: Option<CanonicalLocation>, target: Location) {
+        let key = CacheKey::aggregate(aggregator.name(), &target);
+        if !cache.begin_revalidation(&key) {
+            return;
+        }
+
+        let delay = match Timeout::new(cache.revalidate_after, client.handle()) {
+            Ok(delay) => delay,
+            Err(_) => return cache.end_revalidation(&key)
+        };
+
+        let fetch_client = client.clone();
+        let fetch_cache = cache.clone();
+        let work = delay.map_err(hyper::Error::from)
+            .and_then(move |_| Self::fetch_current(&providers, &fetch_client, &fetch_cache, &guards, &QuorumConfig::default(), &canonical, &target))
+            .then(move |results| {
+                if let Ok(results) = results {
+                    let values: Vec<(&'static str, CurrentObservation)> = results.iter().filter_map(|r| r.result.as_ref().ok().map(|o| (r.name, o.clone()))).collect();
+                    if let Some(observation) = CurrentObservation::aggregate(&values, &*aggregator) {
+                        cache.aggregates.insert(key.clone(), observation);
+                    }
+                }
+                cache.end_revalidation(&key);
+                Ok(())
+            });
+
+        client.handle().spawn(work);
+    }
+
+    // Circuit breaker of every provider. The server is down when all of them are open
+    // and degraded when only some of them are closed
+    fn health_response(&self, format: Format) -> Response {
+        let now = Instant::now();
+        let breakers: Vec<_> = self.providers.iter().map(|p| (p.name(), self.guards.breakers.get(p.name()))).collect();
+
+        let closed = breakers.iter().filter(|&&(_, ref b)| b.state(now) == State::Closed).count();
+        let open = breakers.iter().filter(|&&(_, ref b)| b.state(now) == State::Open).count();
+        let (status, health) = match (closed, open) {
+            (_, open) if open == breakers.len() => (StatusCode::ServiceUnavailable, "down"),
+            (closed, _) if closed == breakers.len() => (StatusCode::Ok, "ok"),
+            _ => (StatusCode::Ok, "degraded")
+        };
+
+        let lines: Vec<String> = breakers.iter().map(|&(name, ref b)|
+            format!("{}: {} (failure rate {:.0}%)", name, b.state(now).name(), b.failure_rate() * 100.0)
+        ).collect();
+        let per_provider: Vec<Value> = breakers.iter().map(|&(name, ref b)| json!({
+            "name": name,
+            "state": b.state(now).name(),
+            "failure_rate": b.failure_rate(),
+            "successes": b.stats().successes,
+            "failures": b.stats().failures,
+            "rejected": b.stats().rejected
+        })).collect();
+
+        let text = format!("status: {}\n{}\n", health, lines.join("\n"));
+        Self::response(status, format, text, json!({ "status": health, "providers": per_provider }))
+    }
+
+    // Provider calls, breaker states and daily budgets in prometheus text format
+    fn metrics_response(&self) -> Response {
+        let now = Instant::now();
+        let mut calls = vec![
+            "# HELP weather_provider_calls_total Provider calls by outcome".to_owned(),
+            "# TYPE weather_provider_calls_total counter".to_owned()
+        ];
+        let mut states = vec![
+            "# HELP weather_provider_circuit_state Circuit breaker state, 0 is closed, 1 half open, 2 open".to_owned(),
+            "# TYPE weather_provider_circuit_state gauge".to_owned()
+        ];
+        let mut budgets = vec![
+            "# HELP weather_provider_calls_today Calls made to the provider during the UTC day".to_owned(),
+            "# TYPE weather_provider_calls_today gauge".to_owned()
+        ];
+
+        for p in self.providers.iter() {
+            let breaker = self.guards.breakers.get(p.name());
+            let stats = breaker.stats();
+            for &(outcome, count) in [("success", stats.successes), ("failure", stats.failures), ("rejected", stats.rejected)].iter() {
+                calls.push(format!("weather_provider_calls_total{{provider=\"{}\",outcome=\"{}\"}} {}", p.name(), outcome, count));
+            }
+
+            let state = match breaker.state(now) {
+                State::Closed => 0,
+                State::HalfOpen => 1,
+                State::Open => 2
+            };
+            states.push(format!("weather_provider_circuit_state{{provider=\"{}\"}} {}", p.name(), state));
+            budgets.push(format!("weather_provider_calls_today{{provider=\"{}\"}} {}", p.name(), self.guards.budgets.calls_today(p.name())));
+        }
+
+        let body = format!("{}\n{}\n{}\n", calls.join("\n"), states.join("\n"), budgets.join("\n"));
+        Self::response(StatusCode::Ok, Format::Text, body, Value::Null)
+    }
+
+    fn response(status: StatusCode, format: Format, text: String, json: Value) -> Response {
+        let (body, content_type) = match format {
+            Format::Text => (text, ContentType::plaintext()),
+            Format::Json => (json.to_string(), ContentType::json())
+        };
+
+        Response::new()
+            .with_header(ContentLength(body.len() as u64))
+            .with_header(content_type)
+            .with_status(status)
+            .with_body(body)
+    }
+
+    fn error_response(status: StatusCode, message: &str, format: Format) -> Response {
+        Self::response(status, format, message.to_owned(), json!({ "error": message }))
+    }
+
+    // Whether every provider failed to find the location
+    fn location_not_found<T>(results: &[ProviderResult<T>]) -> bool {
+        results.iter().all(|r| match r.result {
+            Err(ApiError::LocationNotFound) | Err(ApiError::LocationMismatch) => true,
+            _ => false
+        })
+    }
+
+    // Response for the case when every provider failed, lists their errors.
+    // Providers not finding the location are left out unless all of them did,
+    // the status is the one the rest agree on, 502 when they don't.
+    // Rate limited providers telling when to come back give Retry-After
+    fn failure_response<T>(results: &[ProviderResult<T>], format: Format) -> Response {
+        let location_not_found = Self::location_not_found(results);
+        let statuses: Vec<StatusCode> = results.iter()
+            .filter_map(|r| r.result.as_ref().err())
+            .map(|e| e.http_status())
+            .filter(|status| location_not_found || *status != StatusCode::NotFound)
+            .collect();
+        let status = match statuses.first() {
+            Some(first) if statuses.iter().all(|s| s == first) => *first,
+            _ => StatusCode::BadGateway
+        };
+
+        let message = match status {
+            StatusCode::NotFound => "Location not found",
+            StatusCode::GatewayTimeout => "Providers timed out",
+            StatusCode::ServiceUnavailable => "Providers are unavailable, try again later",
+            _ => "Providers failed to respond"
+        };
+
+        let text = format!("{}\n\n{}", message, format_breakdown(results, |_| String::new()));
+        let per_provider: Vec<Value> = results.iter().map(|r| r.to_json("value", |_| Value::Null)).collect();
+        let json = json!({ "error": message, "providers": per_provider });
+
+        let mut resp = Self::response(status, format, text, json);
+        let retry_after = results.iter().filter_map(|r| match r.result {
+            Err(ApiError::RateLimited(delay)) => delay,
+            _ => None
+        }).min();
+        if let (StatusCode::ServiceUnavailable, Some(delay)) = (status, retry_after) {
+            resp.headers_mut().set(RetryAfter::Delay(delay));
+        }
+        resp
     }
 }
 
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
 impl Service for WeatherServer {
     type Request = Request;
     type Response = Response;
@@ -99,12 +463,11 @@ impl Service for WeatherServer {
 
     fn call(&self, req: Request) -> Self::Future {
 
-        let query = match req.query() {
+        let query = Query::parse(match req.query() {
             Some(s) => s,
             None => ""
-        };
-
-        let mut status = StatusCode::Ok;
+        });
+        let format = Self::response_format(&req, query.as_ref().ok().and_then(|q| q.format));
 
         match (req.method(), req.path()) {
             (&Get, "/") => {
@@ -112,7 +475,10 @@ impl Service for WeatherServer {
                     <body> \
                     examples:<br/> \
                     <a href='/current?tomsk'>current weather in tomsk</a><br/> \
-                    <a href='/forecast?perm'>5 days forecast for perm</a> \
+                    <a href='/forecast?perm'>5 days forecast for perm</a><br/> \
+                    <a href='/current?q=tomsk&format=json'>current weather in tomsk as json</a><br/> \
+                    <a href='/forecast?q=perm&units=imperial&days=3'>3 days forecast for perm in imperial units</a><br/> \
+                    <a href='/health'>provider health</a> \
                     </body> \
                     </html> \
                 ";
@@ -127,88 +493,166 @@ impl Service for WeatherServer {
                 Box::new(resp)
             },
             (&Get, "/current") => {
-                if query.len() == 0 {
-                    return Self::empty_query_body();
-                }
-
-                let async_apixu = apixu::current(&self.handle, query, &self.keys.apixu_key);
-                let async_owm = owm::current(&self.handle, query, &self.keys.owm_key);
-                let async_wb = weatherbit::current(&self.handle, query, &self.keys.weatherbit_key);
-
-                let resp = futures::future::join_all(vec![async_owm, async_apixu, async_wb]).map(move |temps| {
-                    let body = if temps.iter().all(|v| v.is_err()) {
-                        if temps.into_iter().all(|v| v == Err(ApiError::LocationNotFound)) {
-                            status = StatusCode::NotFound;
-                            format!("Location not found")
-                        } else {
-                            status = StatusCode::InternalServerError;
-                            format!("Something went wrong")
+                let query = match query {
+                    Ok(q) => q,
+                    Err(e) => return Self::invalid_query_body(&e, format)
+                };
+                let location = match Self::request_location(&req, &query) {
+                    Some(Ok(l)) => l,
+                    Some(Err(e)) => return Self::invalid_query_body(&e, format),
+                    None => return Self::empty_query_body(format)
+                };
+
+                let providers = self.providers.clone();
+                let client = self.client.clone();
+                let cache = self.cache.clone();
+                let guards = self.guards.clone();
+                let aggregator = aggregator(query.aggregation.unwrap_or(self.config.aggregation.strategy), &self.config.aggregation);
+                let quorum = self.config.quorum;
+                let thresholds = self.config.aggregation.confidence;
+
+                let resp = self.resolve_location(&location, format).and_then(move |resolved| -> <WeatherServer as Service>::Future {
+                    let canonical = match resolved {
+                        Ok(canonical) => canonical,
+                        Err(resp) => return Box::new(futures::future::ok(resp))
+                    };
+                    let target = canonical.as_ref().map(|c| c.location()).unwrap_or_else(|| location.clone());
+
+                    let key = CacheKey::aggregate(aggregator.name(), &target);
+                    let ttl = cache.current.ttl();
+
+                    Box::new(Self::fetch_current(&providers, &client, &cache, &guards, &quorum, &canonical, &target).map(move |results| {
+                        let values: Vec<(&'static str, CurrentObservation)> = results.iter().filter_map(|r| r.result.as_ref().ok().map(|o| (r.name, o.clone()))).collect();
+                        let aggregation = aggregator.name();
+                        let temperatures: Vec<Sample> = values.iter().map(|&(provider, ref o)| Sample { provider: provider, value: o.temperature }).collect();
+                        let spread = Spread::of(&aggregator.used(&temperatures));
+
+                        // with every provider failing the last aggregate for the location is served, if there is one
+                        let (observation, stale_age) = match CurrentObservation::aggregate(&values, &*aggregator) {
+                            Some(observation) => {
+                                cache.aggregates.insert(key, observation.clone());
+                                (observation, None)
+                            },
+                            None => match cache.aggregates.get(&key) {
+                                Some((observation, age)) if !Self::location_not_found(&results) => {
+                                    Self::revalidate_current(&client, providers, cache, guards, aggregator, canonical.clone(), target);
+                                    (observation, Some(age))
+                                },
+                                _ => return Self::failure_response(&results, format)
+                            }
+                        };
+
+                        let units = query.units;
+                        let observation = observation.convert(&units);
+                        let stale_line = stale_age.map(|age| format!("stale: providers are unavailable, showing data received {}s ago\n", age.as_secs())).unwrap_or_default();
+                        let confidence = spread.map(|s| s.temperature_confidence(&thresholds).name());
+                        let spread = spread.map(|s| s.convert(|t| units.temperature.convert(t)));
+                        let spread_line = match (spread, confidence) {
+                            (Some(s), Some(confidence)) => format!("spread: {:.1}{unit} to {:.1}{unit}, stddev {:.1}{unit} across {} providers, confidence {}\n",
+                                s.min, s.max, s.stddev, s.count, confidence, unit = units.temperature.symbol()),
+                            _ => String::new()
+                        };
+                        let text = format!("{}{}{}{}\n{}", Self::location_line(&canonical), stale_line, observation.format(&units, aggregation), spread_line, format_breakdown(&results, |o|
+                            format!("{:.1}{}", units.temperature.convert(o.temperature), units.temperature.symbol())
+                        ));
+                        let per_provider: Vec<Value> = results.iter().map(|r| r.to_json("current", |o| o.convert(&units).to_json())).collect();
+                        let mut json = json!({
+                            "location": Self::location_json(&canonical, &location),
+                            "generated_at": unix_now(),
+                            "units": units.to_json(),
+                            "aggregation": aggregation,
+                            "current": observation.to_json(),
+                            "spread": spread.map(|s| s.to_json()),
+                            "confidence": confidence,
+                            "stale": stale_age.is_some(),
+                            "providers": per_provider
+                        });
+                        if let Some(age) = stale_age {
+                            json["age"] = json!(age.as_secs());
                         }
-                    } else {
-                        let values: Vec<f32> = temps.into_iter().filter_map(|v| v.ok()).collect();
-
-                        let sum: f32 = values.iter().sum::<f32>();
-                        let avg = sum / values.len() as f32;
 
-                        if values.len() > 0 {
-                            format!("avg: {:.1}°C\n", avg)
-                        } else {
-                            format!("Failed to receive APIs responses")
+                        let resp = Self::response(StatusCode::Ok, format, text, json);
+                        match stale_age {
+                            Some(age) => Self::with_stale_headers(resp, age),
+                            None => Self::with_cache_headers(resp, &results, ttl)
                         }
-                    };
-
-
-                    Response::new()
-                            .with_header(ContentLength(body.len() as u64))
-                            .with_header(ContentType::plaintext())
-                            .with_status(status)
-                            .with_body(body)
+                    }))
                 });
 
                 Box::new(resp)
 
             },
             (&Get, "/forecast") => {
-                if query.len() == 0 {
-                    return Self::empty_query_body();
-                }
-
-                let async_apixu = apixu::forecast(&self.handle, query, &self.keys.apixu_key);
-                let async_wb = weatherbit::forecast(&self.handle, query, &self.keys.weatherbit_key);
-
-                let resp = async_apixu.join(async_wb).map(move |(apixu_temp, wb_temp)| {
-                    let body = if apixu_temp.is_err() && wb_temp.is_err() {
-                        if apixu_temp == Err(ApiError::LocationNotFound) && wb_temp == Err(ApiError::LocationNotFound) {
-                            status = StatusCode::NotFound;
-                            format!("Location not found")
-                        } else {
-                            status = StatusCode::InternalServerError;
-                            format!("Something went wrong")
-                        }
-                    } else {
-                        let apixu_temp_vec = apixu_temp.unwrap_or(vec![None; 5]);
-                        let wb_temp_vec = wb_temp.unwrap_or(vec![None; 5]);
-
-                        let avg_temps: Vec<Option<f32>> = apixu_temp_vec.iter().zip(wb_temp_vec.iter()).map(|(&a, &b)| match (a, b) {
-                            (Some(x), Some(y)) => Some((x+y) / 2.0),
-                            (Some(x), None) => Some(x),
-                            (None, Some(y)) => Some(y),
-                            _ => None
-                        }).collect();
-
-                        Self::format_temps(avg_temps)
+                let query = match query {
+                    Ok(q) => q,
+                    Err(e) => return Self::invalid_query_body(&e, format)
+                };
+                let location = match Self::request_location(&req, &query) {
+                    Some(Ok(l)) => l,
+                    Some(Err(e)) => return Self::invalid_query_body(&e, format),
+                    None => return Self::empty_query_body(format)
+                };
+
+                let providers = self.providers.clone();
+                let client = self.client.clone();
+                let cache = self.cache.clone();
+                let guards = self.guards.clone();
+                let aggregator = aggregator(query.aggregation.unwrap_or(self.config.aggregation.strategy), &self.config.aggregation);
+                let quorum = self.config.quorum;
+
+                let resp = self.resolve_location(&location, format).and_then(move |resolved| -> <WeatherServer as Service>::Future {
+                    let canonical = match resolved {
+                        Ok(canonical) => canonical,
+                        Err(resp) => return Box::new(futures::future::ok(resp))
                     };
+                    let target = canonical.as_ref().map(|c| c.location()).unwrap_or_else(|| location.clone());
+
+                    let client = client.with_deadline(quorum.deadline.map(|d| Instant::now() + d));
+                    let requests: Vec<_> = providers.iter()
+                        .filter(|p| p.capabilities().forecast && p.supports(&target))
+                        .map(|p| {
+                            let (provider, client, location) = (p.clone(), client.for_provider(p.name()), target.clone());
+                            (p.name(), guards.fetch(&cache.forecast, p.name(), &target, move || provider.forecast(&client, &location)))
+                        })
+                        .collect();
+                    let ttl = cache.forecast.ttl();
+
+                    Box::new(gather(client.handle(), requests, &quorum).map(move |results| {
+                        let results = match canonical {
+                            Some(ref c) => reject_distant(results, c, |f| f.coordinates),
+                            None => results
+                        };
+
+                        if results.iter().all(|r| r.result.is_err()) {
+                            return Self::failure_response(&results, format);
+                        }
 
+                        let forecasts: Vec<(&'static str, Forecast)> = results.iter().filter_map(|r| r.result.as_ref().ok().map(|f| (r.name, f.clone()))).collect();
+                        let units = query.units;
+                        let mut merged = forecast::merge(forecasts, query.days, &*aggregator, unix_now() as i64).convert(&units);
+                        if let Some(timezone) = canonical.as_ref().and_then(|c| c.timezone.clone()) {
+                            merged.timezone = Some(timezone);
+                        }
 
-                    Response::new()
-                            .with_header(ContentLength(body.len() as u64))
-                            .with_header(ContentType::plaintext())
-                            .with_status(status)
-                            .with_body(body)
+                        let text = format!("{}{}\n\n{}", Self::location_line(&canonical), merged.format(&units), format_breakdown(&results, |f| format!("{} days", f.days.len())));
+                        let per_provider: Vec<Value> = results.iter().map(|r| r.to_json("forecast", |f| f.convert(&units).to_json())).collect();
+                        let json = json!({
+                            "location": Self::location_json(&canonical, &location),
+                            "generated_at": unix_now(),
+                            "units": units.to_json(),
+                            "aggregation": aggregator.name(),
+                            "forecast": merged.to_json(),
+                            "providers": per_provider
+                        });
+
+                        Self::with_cache_headers(Self::response(StatusCode::Ok, format, text, json), &results, ttl)
+                    }))
                 });
 
                 Box::new(resp)
             },
+            (&Get, "/health") => Box::new(futures::future::ok(self.health_response(format))),
+            (&Get, "/metrics") => Box::new(futures::future::ok(self.metrics_response())),
             _ => {
                 let mut resp = Response::new();
                 resp.set_status(StatusCode::NotFound);
@@ -0,0 +1,141 @@
+extern crate serde_json;
+
+use std::fmt;
+use std::net::IpAddr;
+use self::serde_json::Value;
+
+// Place to report weather for, each provider maps it to its own parameters
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    Name(String),
+    Coordinates { lat: f64, lon: f64 },
+    PostalCode { code: String, country: Option<String> },
+    Ip(IpAddr),
+    // client's ip, replaced with `Ip` by the server
+    Auto
+}
+
+impl Location {
+    pub fn coordinates(lat: &str, lon: &str) -> Result<Location, String> {
+        let lat = lat.trim().parse::<f64>().map_err(|_| format!("Invalid lat: {}", lat))?;
+        let lon = lon.trim().parse::<f64>().map_err(|_| format!("Invalid lon: {}", lon))?;
+
+        // "NaN" and "inf" parse as well but fail every range check
+        if !lat.is_finite() || !lon.is_finite() {
+            return Err(format!("lat and lon should be finite numbers"));
+        }
+        if lat < -90.0 || lat > 90.0 {
+            return Err(format!("lat should be from -90 to 90"));
+        }
+        if lon < -180.0 || lon > 180.0 {
+            return Err(format!("lon should be from -180 to 180"));
+        }
+
+        Ok(Location::Coordinates { lat: lat, lon: lon })
+    }
+
+    // Replaces `Auto` with the client's ip
+    pub fn resolve_auto(self, client_ip: Option<IpAddr>) -> Result<Location, String> {
+        match self {
+            Location::Auto => client_ip.map(Location::Ip).ok_or(format!("Unable to determine client ip")),
+            location => Ok(location)
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        match *self {
+            Location::Name(ref name) => json!({ "name": name }),
+            Location::Coordinates { lat, lon } => json!({ "lat": lat, "lon": lon }),
+            Location::PostalCode { ref code, ref country } => json!({ "postal_code": code, "country": country }),
+            Location::Ip(ref ip) => json!({ "ip": ip.to_string() }),
+            Location::Auto => json!({ "ip": "auto" })
+        }
+    }
+}
+
+// Point on the globe in degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coordinates {
+    pub lat: f64,
+    pub lon: f64
+}
+
+// Mean radius of the Earth
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+impl Coordinates {
+    pub fn new(lat: f64, lon: f64) -> Coordinates {
+        Coordinates { lat: lat, lon: lon }
+    }
+
+    // Reads coordinates reported either as numbers or as numeric strings
+    pub fn from_json(lat: &Value, lon: &Value) -> Option<Coordinates> {
+        let number = |v: &Value| v.as_f64().or_else(|| v.as_str().and_then(|s| s.parse::<f64>().ok())).filter(|n| n.is_finite());
+
+        Some(Coordinates::new(number(lat)?, number(lon)?))
+    }
+
+    // Great-circle distance by the haversine formula
+    pub fn distance_km(&self, other: &Coordinates) -> f64 {
+        let d_lat = (other.lat - self.lat).to_radians();
+        let d_lon = (other.lon - self.lon).to_radians();
+
+        let a = (d_lat / 2.0).sin().powi(2)
+            + self.lat.to_radians().cos() * other.lat.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Location::Name(ref name) => f.write_str(name),
+            Location::Coordinates { lat, lon } => write!(f, "{},{}", lat, lon),
+            Location::PostalCode { ref code, country: Some(ref country) } => write!(f, "{},{}", code, country),
+            Location::PostalCode { ref code, country: None } => f.write_str(code),
+            Location::Ip(ref ip) => write!(f, "{}", ip),
+            Location::Auto => f.write_str("auto")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_validates_coordinates() {
+        assert_eq!(Location::coordinates("56.5", "84.97"), Ok(Location::Coordinates { lat: 56.5, lon: 84.97 }));
+        assert_eq!(Location::coordinates("91", "84.97"), Err("lat should be from -90 to 90".to_owned()));
+        assert_eq!(Location::coordinates("56.5", "east"), Err("Invalid lon: east".to_owned()));
+        assert_eq!(Location::coordinates("NaN", "NaN"), Err("lat and lon should be finite numbers".to_owned()));
+        assert_eq!(Location::coordinates("56.5", "inf"), Err("lat and lon should be finite numbers".to_owned()));
+    }
+
+    #[test]
+    fn it_resolves_auto_location() {
+        let ip: IpAddr = "93.184.216.34".parse().unwrap();
+
+        assert_eq!(Location::Auto.resolve_auto(Some(ip)), Ok(Location::Ip(ip)));
+        assert!(Location::Auto.resolve_auto(None).is_err());
+        assert_eq!(Location::Name("tomsk".to_owned()).resolve_auto(Some(ip)), Ok(Location::Name("tomsk".to_owned())));
+    }
+
+    #[test]
+    fn it_measures_distance_between_coordinates() {
+        let tomsk = Coordinates::new(56.5, 84.97);
+        let perm = Coordinates::new(58.0, 56.25);
+
+        assert_eq!(tomsk.distance_km(&tomsk), 0.0);
+        let d = tomsk.distance_km(&perm);
+        assert!(d > 1700.0 && d < 1750.0, "distance {}", d);
+    }
+
+    #[test]
+    fn it_reads_coordinates_from_json() {
+        assert_eq!(Coordinates::from_json(&json!(56.85), &json!("60.61")), Some(Coordinates::new(56.85, 60.61)));
+        assert_eq!(Coordinates::from_json(&json!(null), &json!(60.61)), None);
+        assert_eq!(Coordinates::from_json(&json!("NaN"), &json!(60.61)), None);
+    }
+}
@@ -3,24 +3,17 @@ extern crate tokio_core;
 extern crate hyper;
 extern crate futures;
 
-use std::env::var;
-
 fn main() {
-    let weatherbit_key = match var("WEATHERBIT_KEY") {
-        Ok(v) => v,
-        Err(e) => panic!("WEATHERBIT_KEY is absent, {:?}", e),
+    let keys = match weather::ApiKeys::from_env() {
+        Ok(k) => k,
+        Err(e) => panic!("{}", e),
     };
 
-    let apixu_key = match var("APIXU_KEY") {
-        Ok(v) => v,
-        Err(e) => panic!("APIXU_KEY is absent, {:?}", e),
-    };
-    let owm_key = match var("OWM_KEY") {
-        Ok(v) => v,
-        Err(e) => panic!("OWM_KEY is absent, {:?}", e),
+    let config = match weather::config::Config::from_env() {
+        Ok(c) => c,
+        Err(e) => panic!("{}", e),
     };
 
-    let keys = weather::ApiKeys::new(owm_key, apixu_key, weatherbit_key);
-    let mut core = weather::start_server("0.0.0.0:1337", keys);
+    let mut core = weather::start_server("0.0.0.0:1337", keys, config);
     core.run(futures::future::empty::<(), ()>()).unwrap();
 }
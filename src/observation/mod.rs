@@ -0,0 +1,277 @@
+extern crate serde_json;
+
+use std::f32::consts::PI;
+use self::serde_json::Value;
+
+use units::Units;
+use location::Coordinates;
+use aggregation::{Aggregator, combine};
+
+// Current weather reported by a provider, in metric units:
+// temperature in °C, humidity and cloud cover in %, pressure in hPa,
+// wind speed in m/s, wind direction in degrees, visibility in km,
+// precipitation in mm; observation time is a unix timestamp;
+// coordinates are those of the place the provider reported for
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CurrentObservation {
+    pub temperature: f32,
+    pub humidity: Option<f32>,
+    pub pressure: Option<f32>,
+    pub wind_speed: Option<f32>,
+    pub wind_direction: Option<f32>,
+    pub cloud_cover: Option<f32>,
+    pub visibility: Option<f32>,
+    pub precipitation: Option<f32>,
+    pub condition: Option<String>,
+    pub observed_at: Option<i64>,
+    pub coordinates: Option<Coordinates>
+}
+
+impl CurrentObservation {
+    pub fn new(temperature: f32) -> CurrentObservation {
+        CurrentObservation { temperature: temperature, ..Default::default() }
+    }
+
+    // Combines observations of several providers field by field with `aggregator`,
+    // wind direction is averaged as an angle and the most common condition is taken.
+    // Returns None if there is nothing to combine
+    pub fn aggregate(observations: &[(&'static str, CurrentObservation)], aggregator: &Aggregator) -> Option<CurrentObservation> {
+        let field = |f: &Fn(&CurrentObservation) -> Option<f32>| combine(aggregator, observations.iter().map(|&(p, ref o)| (p, f(o))));
+
+        Some(CurrentObservation {
+            temperature: field(&|o| Some(o.temperature))?,
+            humidity: field(&|o| o.humidity),
+            pressure: field(&|o| o.pressure),
+            wind_speed: field(&|o| o.wind_speed),
+            wind_direction: mean_direction(observations.iter().map(|&(_, ref o)| o.wind_direction)),
+            cloud_cover: field(&|o| o.cloud_cover),
+            visibility: field(&|o| o.visibility),
+            precipitation: field(&|o| o.precipitation),
+            condition: most_common(observations.iter().map(|&(_, ref o)| o.condition.clone())),
+            // age of the combined observation is the age of its oldest part
+            observed_at: observations.iter().filter_map(|&(_, ref o)| o.observed_at).min(),
+            coordinates: None
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "temperature": self.temperature,
+            "humidity": self.humidity,
+            "pressure": self.pressure,
+            "wind_speed": self.wind_speed,
+            "wind_direction": self.wind_direction,
+            "cloud_cover": self.cloud_cover,
+            "visibility": self.visibility,
+            "precipitation": self.precipitation,
+            "condition": self.condition,
+            "observed_at": self.observed_at
+        })
+    }
+
+    // Converts metric values into `units`
+    pub fn convert(&self, units: &Units) -> CurrentObservation {
+        CurrentObservation {
+            temperature: units.temperature.convert(self.temperature),
+            pressure: self.pressure.map(|v| units.pressure.convert(v)),
+            wind_speed: self.wind_speed.map(|v| units.wind_speed.convert(v)),
+            visibility: self.visibility.map(|v| units.visibility.convert(v)),
+            precipitation: self.precipitation.map(|v| units.precipitation.convert(v)),
+            ..self.clone()
+        }
+    }
+
+    // Formats values already converted into `units`, the temperature is labeled with
+    // the name of the aggregation it was combined by, the default mean keeps the `avg` label
+    pub fn format(&self, units: &Units, aggregation: &str) -> String {
+        let label = if aggregation == "mean" { "avg" } else { aggregation };
+        let mut lines = vec![format!("{}: {:.1}{}", label, self.temperature, units.temperature.symbol())];
+
+        if let Some(v) = self.humidity {
+            lines.push(format!("humidity: {:.0}%", v));
+        }
+        if let Some(v) = self.pressure {
+            lines.push(format!("pressure: {:.*}{}", units.pressure.precision(), v, units.pressure.symbol()));
+        }
+        match (self.wind_speed, self.wind_direction) {
+            (Some(s), Some(d)) => lines.push(format!("wind: {:.1}{} from {:.0}°", s, units.wind_speed.symbol(), d)),
+            (Some(s), None) => lines.push(format!("wind: {:.1}{}", s, units.wind_speed.symbol())),
+            _ => {}
+        }
+        if let Some(v) = self.cloud_cover {
+            lines.push(format!("clouds: {:.0}%", v));
+        }
+        if let Some(v) = self.visibility {
+            lines.push(format!("visibility: {:.1}{}", v, units.visibility.symbol()));
+        }
+        if let Some(v) = self.precipitation {
+            lines.push(format!("precipitation: {:.*}{}", units.precipitation.precision(), v, units.precipitation.symbol()));
+        }
+        if let Some(ref v) = self.condition {
+            lines.push(format!("conditions: {}", v));
+        }
+
+        lines.join("\n") + "\n"
+    }
+}
+
+// Reads a json number as f32, None for null, absent or non-numeric values
+pub fn json_f32(v: &Value) -> Option<f32> {
+    v.as_f64().map(|n| n as f32)
+}
+
+pub fn json_string(v: &Value) -> Option<String> {
+    v.as_str().map(|s| s.to_owned())
+}
+
+pub fn mean<I: Iterator<Item = Option<f32>>>(values: I) -> Option<f32> {
+    let present: Vec<f32> = values.filter_map(|v| v).collect();
+    if present.len() > 0 {
+        Some(present.iter().sum::<f32>() / present.len() as f32)
+    } else {
+        None
+    }
+}
+
+pub fn minimum<I: Iterator<Item = Option<f32>>>(values: I) -> Option<f32> {
+    values.filter_map(|v| v).fold(None, |acc, v| Some(acc.map_or(v, |a: f32| a.min(v))))
+}
+
+pub fn maximum<I: Iterator<Item = Option<f32>>>(values: I) -> Option<f32> {
+    values.filter_map(|v| v).fold(None, |acc, v| Some(acc.map_or(v, |a: f32| a.max(v))))
+}
+
+// Averages angles in degrees as unit vectors, so 350° and 10° give 0°
+pub fn mean_direction<I: Iterator<Item = Option<f32>>>(values: I) -> Option<f32> {
+    let present: Vec<f32> = values.filter_map(|v| v).collect();
+    if present.is_empty() {
+        return None;
+    }
+
+    let (x, y) = present.iter().fold((0.0f32, 0.0f32), |(x, y), d| {
+        let rad = d * PI / 180.0;
+        (x + rad.cos(), y + rad.sin())
+    });
+    let deg = y.atan2(x) * 180.0 / PI;
+
+    Some(if deg < 0.0 { deg + 360.0 } else { deg })
+}
+
+// Most frequent value, ties are resolved in favor of the first one seen
+pub fn most_common<I: Iterator<Item = Option<String>>>(values: I) -> Option<String> {
+    let present: Vec<String> = values.filter_map(|v| v).collect();
+
+    let mut best: Option<(usize, &String)> = None;
+    for v in present.iter() {
+        let count = present.iter().filter(|o| o.to_lowercase() == v.to_lowercase()).count();
+        match best {
+            Some((c, _)) if c >= count => {},
+            _ => best = Some((count, v))
+        }
+    }
+
+    best.map(|(_, v)| v.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aggregation::Mean;
+
+    #[test]
+    fn aggregate_averages_present_fields() {
+        let a = CurrentObservation {
+            temperature: -10.0,
+            humidity: Some(80.0),
+            pressure: Some(1030.0),
+            wind_speed: Some(2.0),
+            wind_direction: Some(350.0),
+            cloud_cover: None,
+            visibility: Some(10.0),
+            precipitation: None,
+            condition: Some("Fog".to_owned()),
+            observed_at: Some(1517661000),
+            coordinates: None
+        };
+        let b = CurrentObservation {
+            temperature: -14.0,
+            humidity: Some(60.0),
+            pressure: None,
+            wind_speed: Some(4.0),
+            wind_direction: Some(10.0),
+            cloud_cover: Some(75.0),
+            visibility: None,
+            precipitation: None,
+            condition: Some("Overcast".to_owned()),
+            observed_at: Some(1517661028),
+            coordinates: None
+        };
+
+        let r = CurrentObservation::aggregate(&[("owm", a), ("apixu", b)], &Mean).unwrap();
+
+        assert_eq!(r.temperature, -12.0);
+        assert_eq!(r.humidity, Some(70.0));
+        assert_eq!(r.pressure, Some(1030.0));
+        assert_eq!(r.wind_speed, Some(3.0));
+        assert!(r.wind_direction.unwrap() < 0.01 || r.wind_direction.unwrap() > 359.99);
+        assert_eq!(r.cloud_cover, Some(75.0));
+        assert_eq!(r.visibility, Some(10.0));
+        assert_eq!(r.precipitation, None);
+        assert_eq!(r.condition, Some("Fog".to_owned()));
+        assert_eq!(r.observed_at, Some(1517661000));
+    }
+
+    #[test]
+    fn aggregate_of_nothing_is_none() {
+        assert_eq!(CurrentObservation::aggregate(&[], &Mean), None);
+    }
+
+    #[test]
+    fn most_common_prefers_majority() {
+        let values = vec![Some("Fog".to_owned()), None, Some("Snow".to_owned()), Some("snow".to_owned())];
+        assert_eq!(most_common(values.into_iter()), Some("Snow".to_owned()));
+    }
+
+    #[test]
+    fn format_skips_missing_fields() {
+        let mut o = CurrentObservation::new(-14.0);
+        o.humidity = Some(78.0);
+        o.condition = Some("Overcast".to_owned());
+
+        assert_eq!(o.format(&Units::metric(), "median"), "median: -14.0°C\nhumidity: 78%\nconditions: Overcast\n");
+    }
+
+    #[test]
+    fn convert_changes_only_dimensional_fields() {
+        let mut o = CurrentObservation::new(-10.0);
+        o.humidity = Some(78.0);
+        o.pressure = Some(1013.0);
+        o.wind_speed = Some(10.0);
+        o.wind_direction = Some(90.0);
+        o.precipitation = Some(2.54);
+
+        let units = Units::imperial();
+        let r = o.convert(&units);
+
+        assert_eq!(r.temperature, 14.0);
+        assert_eq!(r.humidity, Some(78.0));
+        assert_eq!(r.wind_direction, Some(90.0));
+        assert_eq!(
+            r.format(&units, "mean"),
+            "avg: 14.0°F\nhumidity: 78%\npressure: 29.91inHg\nwind: 22.4mph from 90°\nprecipitation: 0.10in\n"
+        );
+    }
+
+    #[test]
+    fn to_json_keeps_missing_fields_as_null() {
+        let mut o = CurrentObservation::new(-14.0);
+        o.humidity = Some(78.0);
+
+        let json = o.to_json();
+
+        assert_eq!(json["temperature"], -14.0);
+        assert_eq!(json["humidity"], 78.0);
+        assert!(json["pressure"].is_null());
+        assert!(json["condition"].is_null());
+    }
+}
@@ -5,45 +5,153 @@ extern crate hyper;
 extern crate tokio_core;
 extern crate serde_json;
 
+use std::collections::BTreeMap;
 use self::serde_json::Value;
-use std::io;
-use self::futures::{Future};
-
-use self::tokio_core::reactor::{Handle};
-use async_request::async_json_request;
-use async_request::Result;
+use async_request::{async_api_request, ApiFuture, AsyncJsonResponse};
 use async_request::error::ApiError;
+use async_request::client::HttpClient;
+use keys::KeyRing;
+use provider::{WeatherProvider, Capabilities, unsupported_location};
+use query::encode_component;
+use location::{Location, Coordinates};
+use observation::{CurrentObservation, json_f32, json_string, mean, minimum, maximum, most_common};
+use forecast::{Forecast, ForecastDate, DailyForecast};
 
 #[cfg(not(test))]
-const API_ROOT: &'static str = "http://api.openweathermap.org/data/2.5";
+const API_ROOT: &'static str = "https://api.openweathermap.org/data/2.5";
 
-pub fn current(handle: &Handle, q: &str, api_key: &str) -> Box<Future<Item = Result<f32>, Error = hyper::Error>> {
-    let url = format!("{api_root}/weather?q={loc}&APPID={key}&units=metric", loc=q, key=api_key, api_root=API_ROOT);
+pub struct Owm {
+    keys: KeyRing
+}
 
-    let resp = async_json_request(handle, &url).and_then(|s| {
-        if s.status == hyper::StatusCode::NotFound {
-            return Ok(Err(ApiError::LocationNotFound));
-        }
+impl Owm {
+    pub fn new(keys: KeyRing) -> Owm {
+        Owm { keys: keys }
+    }
+}
+
+impl WeatherProvider for Owm {
+    fn name(&self) -> &'static str {
+        "owm"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { current: true, forecast: true }
+    }
+
+    fn supports(&self, location: &Location) -> bool {
+        location_params(location).is_some()
+    }
+
+    fn current(&self, client: &HttpClient, location: &Location) -> ApiFuture<CurrentObservation> {
+        current(client, location, &self.keys)
+    }
+
+    fn forecast(&self, client: &HttpClient, location: &Location) -> ApiFuture<Forecast> {
+        forecast(client, location, &self.keys)
+    }
+}
+
+pub fn current(client: &HttpClient, location: &Location, keys: &KeyRing) -> ApiFuture<CurrentObservation> {
+    let loc = match location_params(location) {
+        Some(params) => params,
+        None => return unsupported_location()
+    };
+    let url = move |key: &str| format!("{api_root}/weather?{loc}&APPID={key}&units=metric", loc=loc, key=encode_component(key), api_root=API_ROOT);
+
+    async_api_request(client, keys, url, api_error, |body| {
+        let temp = serde_json::from_value::<f32>(body["main"]["temp"].clone())?;
+
+        Ok(CurrentObservation {
+            temperature: temp,
+            humidity: json_f32(&body["main"]["humidity"]),
+            pressure: json_f32(&body["main"]["pressure"]),
+            wind_speed: json_f32(&body["wind"]["speed"]),
+            wind_direction: json_f32(&body["wind"]["deg"]),
+            cloud_cover: json_f32(&body["clouds"]["all"]),
+            visibility: body["visibility"].as_f64().map(|m| (m / 1000.0) as f32),
+            precipitation: precipitation(&body, "1h"),
+            condition: json_string(&body["weather"][0]["description"]),
+            observed_at: body["dt"].as_i64(),
+            coordinates: Coordinates::from_json(&body["coord"]["lat"], &body["coord"]["lon"])
+        })
+    })
+}
+
+// 5 day forecast with 3 hour step, rolled up into days local to the location
+pub fn forecast(client: &HttpClient, location: &Location, keys: &KeyRing) -> ApiFuture<Forecast> {
+    let loc = match location_params(location) {
+        Some(params) => params,
+        None => return unsupported_location()
+    };
+    let url = move |key: &str| format!("{api_root}/forecast?{loc}&APPID={key}&units=metric", loc=loc, key=encode_component(key), api_root=API_ROOT);
 
-        if s.body.is_none() {
-            return Ok(Err(ApiError::Other));
+    async_api_request(client, keys, url, api_error, |body| {
+        let entries: Vec<Value> = serde_json::from_value(body["list"].clone())?;
+        // shift in seconds from UTC, days can't be told apart without it
+        let utc_offset = serde_json::from_value::<i64>(body["city"]["timezone"].clone())?;
+
+        let mut by_date: BTreeMap<ForecastDate, Vec<Value>> = BTreeMap::new();
+        for entry in entries {
+            if let Some(ts) = entry["dt"].as_i64() {
+                by_date.entry(ForecastDate::from_timestamp(ts, utc_offset)).or_insert_with(Vec::new).push(entry);
+            }
         }
 
-        let body = s.body.unwrap();
-        let json_temp: Value = body["main"]["temp"].clone();
-        let temp = serde_json::from_value::<f32>(json_temp).map_err(|e|
-            io::Error::new(
-                io::ErrorKind::Other,
-                e
-            )
-        )?;
-        Ok(Ok(temp))
-
-    }).or_else(|_s|
-        Ok(Err(ApiError::Other))
-    );
-
-    Box::new(resp)
+        Ok(Forecast {
+            timezone: None,
+            utc_offset: Some(utc_offset),
+            coordinates: Coordinates::from_json(&body["city"]["coord"]["lat"], &body["city"]["coord"]["lon"]),
+            days: by_date.into_iter().map(|(date, entries)| (date, rollup(&entries))).collect()
+        })
+    })
+}
+
+// Combines 3 hour forecast entries of a single day
+fn rollup(entries: &[Value]) -> DailyForecast {
+    let precipitations: Vec<f32> = entries.iter().filter_map(|e| precipitation(e, "3h")).collect();
+
+    DailyForecast {
+        temperature: mean(entries.iter().map(|e| json_f32(&e["main"]["temp"]))),
+        min_temperature: minimum(entries.iter().map(|e| json_f32(&e["main"]["temp_min"]))),
+        max_temperature: maximum(entries.iter().map(|e| json_f32(&e["main"]["temp_max"]))),
+        precipitation: if precipitations.is_empty() { None } else { Some(precipitations.iter().sum()) },
+        precipitation_probability: maximum(entries.iter().map(|e| json_f32(&e["pop"]).map(|p| p * 100.0))),
+        wind_speed: mean(entries.iter().map(|e| json_f32(&e["wind"]["speed"]))),
+        condition: most_common(entries.iter().map(|e| json_string(&e["weather"][0]["description"])))
+    }
+}
+
+// Sum of rain and snow over the period ("1h" or "3h"),
+// both are omitted by owm when there is none
+fn precipitation(v: &Value, period: &str) -> Option<f32> {
+    let rain = json_f32(&v["rain"][period]);
+    let snow = json_f32(&v["snow"][period]);
+
+    match (rain, snow) {
+        (None, None) => None,
+        (r, s) => Some(r.unwrap_or(0.0) + s.unwrap_or(0.0))
+    }
+}
+
+// Unknown places are answered with 404, invalid keys and blocked
+// accounts with the usual 401 and 429
+fn api_error(response: &AsyncJsonResponse) -> Option<ApiError> {
+    match response.status {
+        hyper::StatusCode::NotFound => Some(ApiError::LocationNotFound),
+        _ => None
+    }
+}
+
+// Location as owm query parameters, owm is unable to locate ips
+fn location_params(location: &Location) -> Option<String> {
+    match *location {
+        Location::Name(ref name) => Some(format!("q={}", encode_component(name))),
+        Location::Coordinates { lat, lon } => Some(format!("lat={}&lon={}", lat, lon)),
+        Location::PostalCode { ref code, country: Some(ref country) } => Some(format!("zip={},{}", encode_component(code), encode_component(country))),
+        Location::PostalCode { ref code, country: None } => Some(format!("zip={}", encode_component(code))),
+        Location::Ip(_) | Location::Auto => None
+    }
 }
 
 #[cfg(test)]
@@ -55,7 +163,13 @@ const API_ROOT: &'static str = mockito::SERVER_URL;
 #[cfg(test)]
 mod tests {
     use super::*;
+    use self::futures::Future;
     use self::mockito::{mock, Matcher};
+    use keys::KeysConfig;
+
+    fn key(key: &str) -> KeyRing {
+        KeyRing::new(&[key.to_owned()], KeysConfig::default())
+    }
 
     #[test]
     fn it_performs_request_to_api() {
@@ -66,12 +180,71 @@ mod tests {
             .create();
 
         let mut core = tokio_core::reactor::Core::new().unwrap();
-        let handle = core.handle();
-        let work = current(&handle, "Yakutsk", "");
+        let client = HttpClient::new(&core.handle());
+        let work = current(&client, &Location::Name("Yakutsk".to_owned()), &key(""));
         let r = core.run(work);
 
-        assert_eq!(r.unwrap(), Ok(-41.0));
+        assert_eq!(r.unwrap(), Ok(CurrentObservation {
+            temperature: -41.0,
+            humidity: Some(57.0),
+            pressure: Some(1038.0),
+            wind_speed: Some(1.27),
+            wind_direction: Some(10.0068),
+            cloud_cover: Some(8.0),
+            visibility: Some(0.2),
+            precipitation: None,
+            condition: Some("fog".to_owned()),
+            observed_at: Some(1517661000),
+            coordinates: Some(Coordinates::new(62.03, 129.73))
+        }));
+        m1.assert();
+    }
+
+    #[test]
+    fn it_encodes_location() {
+        let m = mock("GET", Matcher::Regex(r#"^/weather\?q=New%20York%26units%3Dimperial&APPID=.*"#.to_string()))
+            .with_status(404)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"cod":"404","message":"city not found"}"#)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let work = current(&client, &Location::Name("New York&units=imperial".to_owned()), &key(""));
+        let r = core.run(work);
+
+        assert_eq!(r.unwrap(), Err(ApiError::LocationNotFound));
+        m.assert();
+    }
+
+    #[test]
+    fn it_queries_by_coordinates_and_postal_code() {
+        let m1 = mock("GET", Matcher::Regex(r#"^/weather\?lat=62.03&lon=129.73&APPID=.*"#.to_string()))
+            .with_status(404)
+            .with_body(r#"{"cod":"404","message":"city not found"}"#)
+            .create();
+        let m2 = mock("GET", Matcher::Regex(r#"^/weather\?zip=94040,us&APPID=.*"#.to_string()))
+            .with_status(404)
+            .with_body(r#"{"cod":"404","message":"city not found"}"#)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let by_coordinates = current(&client, &Location::Coordinates { lat: 62.03, lon: 129.73 }, &key(""));
+        let by_postal_code = current(&client, &Location::PostalCode { code: "94040".to_owned(), country: Some("us".to_owned()) }, &key(""));
+        let r = core.run(by_coordinates.join(by_postal_code));
+
+        assert_eq!(r.unwrap(), (Err(ApiError::LocationNotFound), Err(ApiError::LocationNotFound)));
         m1.assert();
+        m2.assert();
+    }
+
+    #[test]
+    fn it_does_not_support_ips() {
+        let owm = Owm::new(KeyRing::new(&[], KeysConfig::default()));
+
+        assert!(owm.supports(&Location::Name("Yakutsk".to_owned())));
+        assert!(!owm.supports(&Location::Ip("93.184.216.34".parse().unwrap())));
     }
 
     #[test]
@@ -82,11 +255,95 @@ mod tests {
             .with_body(r#"{"cod":"404","message":"city not found"}"#)
             .create();
         let mut core = tokio_core::reactor::Core::new().unwrap();
-        let handle = core.handle();
-        let work = current(&handle, "new-rk", "");
+        let client = HttpClient::new(&core.handle());
+        let work = current(&client, &Location::Name("new-rk".to_owned()), &key(""));
         let r = core.run(work);
 
         assert_eq!(r.unwrap(), Err(ApiError::LocationNotFound));
         m2.assert();
     }
+
+    #[test]
+    fn forecast_handles_proper_response() {
+        let m = mock("GET", Matcher::Regex(r#"^/forecast.*Yakutsk.*"#.to_string()))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"cod":"200","message":0.0036,"cnt":4,"list":[{"dt":1517659200,"main":{"temp":-38,"temp_min":-39,"temp_max":-37.5,"pressure":1040,"humidity":60},"weather":[{"id":600,"main":"Snow","description":"light snow","icon":"13n"}],"clouds":{"all":40},"wind":{"speed":1.5,"deg":20},"snow":{"3h":0.25},"pop":0.3,"dt_txt":"2018-02-03 12:00:00"},{"dt":1517670000,"main":{"temp":-42,"temp_min":-42.5,"temp_max":-41,"pressure":1041,"humidity":58},"weather":[{"id":800,"main":"Clear","description":"clear sky","icon":"01n"}],"clouds":{"all":0},"wind":{"speed":1,"deg":10},"pop":0,"dt_txt":"2018-02-03 15:00:00"},{"dt":1517680800,"main":{"temp":-44,"temp_min":-44,"temp_max":-43,"pressure":1042,"humidity":57},"weather":[{"id":800,"main":"Clear","description":"clear sky","icon":"01n"}],"clouds":{"all":0},"wind":{"speed":2,"deg":15},"snow":{"3h":0.5},"pop":0.2,"dt_txt":"2018-02-03 18:00:00"},{"dt":1517691600,"main":{"temp":-40,"temp_min":-40,"temp_max":-39,"pressure":1042,"humidity":59},"weather":[{"id":600,"main":"Snow","description":"light snow","icon":"13d"}],"clouds":{"all":30},"wind":{"speed":3,"deg":5},"pop":0.1,"dt_txt":"2018-02-03 21:00:00"}],"city":{"id":2013159,"name":"Yakutsk","coord":{"lat":62.0339,"lon":129.7331},"country":"RU","timezone":32400}}"#)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let work = forecast(&client, &Location::Name("Yakutsk".to_owned()), &key(""));
+        let r = core.run(work);
+
+        let forecast = r.unwrap().unwrap();
+        let dates: Vec<String> = forecast.days.keys().map(|d| d.to_string()).collect();
+        assert_eq!(dates, vec!["2018-02-03", "2018-02-04"]);
+        assert_eq!(forecast.utc_offset, Some(32400));
+        assert_eq!(forecast.coordinates, Some(Coordinates::new(62.0339, 129.7331)));
+
+        assert_eq!(forecast.days[&ForecastDate::new(2018, 2, 4)], DailyForecast {
+            temperature: Some(-42.0),
+            min_temperature: Some(-44.0),
+            max_temperature: Some(-39.0),
+            precipitation: Some(0.5),
+            precipitation_probability: Some(20.0),
+            wind_speed: Some(2.0),
+            condition: Some("clear sky".to_owned())
+        });
+        m.assert();
+    }
+
+    #[test]
+    fn forecast_handles_wrong_cities() {
+        let m = mock("GET", Matcher::Regex(r#"^/forecast.*new-rk.*"#.to_string()))
+            .with_status(404)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"cod":"404","message":"city not found"}"#)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let work = forecast(&client, &Location::Name("new-rk".to_owned()), &key(""));
+        let r = core.run(work);
+
+        assert_eq!(r.unwrap(), Err(ApiError::LocationNotFound));
+        m.assert();
+    }
+
+    #[test]
+    fn forecast_requires_utc_offset() {
+        let m = mock("GET", Matcher::Regex(r#"^/forecast.*Oymyakon.*"#.to_string()))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"cod":"200","cnt":1,"list":[{"dt":1517659200,"main":{"temp":-48,"temp_min":-49,"temp_max":-47}}],"city":{"name":"Oymyakon","coord":{"lat":63.4608,"lon":142.7858},"country":"RU"}}"#)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let work = forecast(&client, &Location::Name("Oymyakon".to_owned()), &key(""));
+
+        match core.run(work).unwrap() {
+            Err(ApiError::MalformedResponse(_)) => {},
+            other => panic!("unexpected {:?}", other)
+        }
+        m.assert();
+    }
+
+    #[test]
+    fn it_reports_invalid_keys() {
+        let m = mock("GET", Matcher::Regex(r#"^/weather.*revoked-key.*"#.to_string()))
+            .with_status(401)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"cod":401,"message":"Invalid API key. Please see http://openweathermap.org/faq#error401 for more info."}"#)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let work = current(&client, &Location::Name("tomsk".to_owned()), &key("revoked-key"));
+        let r = core.run(work);
+
+        assert_eq!(r.unwrap(), Err(ApiError::InvalidApiKey));
+        m.assert();
+    }
 }
@@ -0,0 +1,183 @@
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+extern crate serde_json;
+
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use self::futures::Future;
+use self::serde_json::Value;
+
+use async_request::{ApiFuture, Result};
+use async_request::client::HttpClient;
+use async_request::error::ApiError;
+use observation::CurrentObservation;
+use forecast::Forecast;
+use location::Location;
+use owm::Owm;
+use apixu::Apixu;
+use weatherbit::Weatherbit;
+use keys::KeyRings;
+
+// What kind of data a provider is able to report
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    pub current: bool,
+    pub forecast: bool
+}
+
+pub trait WeatherProvider {
+    fn name(&self) -> &'static str;
+
+    fn capabilities(&self) -> Capabilities;
+
+    // Whether the provider is able to look up the location
+    fn supports(&self, _location: &Location) -> bool {
+        true
+    }
+
+    fn current(&self, client: &HttpClient, location: &Location) -> ApiFuture<CurrentObservation>;
+
+    // Daily forecasts for the next days keyed by date local to the location
+    fn forecast(&self, _client: &HttpClient, _location: &Location) -> ApiFuture<Forecast> {
+        Box::new(futures::future::ok(Err(ApiError::Other)))
+    }
+}
+
+pub type Providers = Vec<Rc<WeatherProvider>>;
+
+// Result of a call with location the provider is unable to look up
+pub fn unsupported_location<T: 'static>() -> ApiFuture<T> {
+    Box::new(futures::future::ok(Err(ApiError::Other)))
+}
+
+// All providers the server queries, in order of appearance in responses
+pub fn registry(keys: &KeyRings) -> Providers {
+    vec![
+        Rc::new(Owm::new(keys.owm.clone())),
+        Rc::new(Apixu::new(keys.apixu.clone())),
+        Rc::new(Weatherbit::new(keys.weatherbit.clone())),
+    ]
+}
+
+// Outcome of a single provider call
+#[derive(Debug)]
+pub struct ProviderResult<T> {
+    pub name: &'static str,
+    pub result: Result<T>,
+    pub latency: Duration,
+    // age of the response taken from the cache
+    pub age: Option<Duration>
+}
+
+impl<T> ProviderResult<T> {
+    pub fn latency_ms(&self) -> u64 {
+        self.latency.as_secs() * 1000 + (self.latency.subsec_nanos() / 1_000_000) as u64
+    }
+
+    // Line like "owm: -41.0°C (120ms)", "apixu: Timeout (5000ms)"
+    // or "weatherbit: -40.0°C (cached 30s ago)"
+    pub fn format<F: Fn(&T) -> String>(&self, describe: F) -> String {
+        let outcome = match self.result {
+            Ok(ref v) => describe(v),
+            Err(ref e) => e.to_string()
+        };
+        match self.age {
+            Some(age) => format!("{}: {} (cached {}s ago)", self.name, outcome, age.as_secs()),
+            None => format!("{}: {} ({}ms)", self.name, outcome, self.latency_ms())
+        }
+    }
+
+    // Successful result is reported under `key`
+    pub fn to_json<F: Fn(&T) -> Value>(&self, key: &str, describe: F) -> Value {
+        let mut json = json!({
+            "name": self.name,
+            "latency_ms": self.latency_ms()
+        });
+
+        if let Some(age) = self.age {
+            json["cache_age"] = json!(age.as_secs());
+        }
+
+        match self.result {
+            Ok(ref v) => {
+                json["status"] = json!("ok");
+                json[key] = describe(v);
+            },
+            Err(ApiError::Skipped) => {
+                json["status"] = json!("skipped");
+            },
+            Err(ref e) => {
+                json["status"] = json!("error");
+                json["error"] = json!(e.to_string());
+            }
+        }
+
+        json
+    }
+}
+
+// Measures how long a provider takes to respond
+pub fn timed<T: 'static>(name: &'static str, request: ApiFuture<T>) -> Box<Future<Item = ProviderResult<T>, Error = hyper::Error>> {
+    let started = Instant::now();
+
+    Box::new(request.map(move |result| ProviderResult {
+        name: name,
+        result: result,
+        latency: started.elapsed(),
+        age: None
+    }))
+}
+
+pub fn format_breakdown<T, F: Fn(&T) -> String>(results: &[ProviderResult<T>], describe: F) -> String {
+    let lines: Vec<String> = results.iter().map(|r| r.format(&describe)).collect();
+    format!("providers:\n{}\n", lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keys::KeysConfig;
+    use budget::{Budgets, BudgetConfig};
+    use ApiKeys;
+
+    #[test]
+    fn registry_contains_all_providers() {
+        let keys = ApiKeys::new(vec!["owm".to_owned()], vec!["apixu".to_owned()], vec!["weatherbit".to_owned()]);
+        let core = tokio_core::reactor::Core::new().unwrap();
+        let providers = registry(&KeyRings::new(&keys, KeysConfig::default(), &Budgets::new(&core.handle(), BudgetConfig::default())));
+
+        let names: Vec<&str> = providers.iter().map(|p| p.name()).collect();
+        assert_eq!(names, vec!["owm", "apixu", "weatherbit"]);
+
+        let forecasting: Vec<&str> = providers.iter().filter(|p| p.capabilities().forecast).map(|p| p.name()).collect();
+        assert_eq!(forecasting, vec!["owm", "apixu", "weatherbit"]);
+    }
+
+    #[test]
+    fn results_report_value_or_error() {
+        let ok = ProviderResult { name: "owm", result: Ok(-41.0), latency: Duration::from_millis(120), age: None };
+        let failed: ProviderResult<f32> = ProviderResult { name: "apixu", result: Err(ApiError::Timeout), latency: Duration::from_millis(5000), age: None };
+        let cached = ProviderResult { name: "weatherbit", result: Ok(-40.0), latency: Duration::from_millis(0), age: Some(Duration::from_secs(30)) };
+        let skipped: ProviderResult<f32> = ProviderResult { name: "apixu", result: Err(ApiError::Skipped), latency: Duration::from_millis(1500), age: None };
+
+        assert_eq!(ok.format(|t| format!("{:.1}°C", t)), "owm: -41.0°C (120ms)");
+        assert_eq!(failed.format(|t| format!("{:.1}°C", t)), "apixu: Timeout (5000ms)");
+        assert_eq!(cached.format(|t| format!("{:.1}°C", t)), "weatherbit: -40.0°C (cached 30s ago)");
+        assert_eq!(skipped.format(|t| format!("{:.1}°C", t)), "apixu: Skipped (1500ms)");
+
+        assert_eq!(ok.to_json("temperature", |t| json!(t)), json!({
+            "name": "owm", "status": "ok", "latency_ms": 120, "temperature": -41.0
+        }));
+        assert_eq!(failed.to_json("temperature", |t| json!(t)), json!({
+            "name": "apixu", "status": "error", "latency_ms": 5000, "error": "Timeout"
+        }));
+        assert_eq!(cached.to_json("temperature", |t| json!(t)), json!({
+            "name": "weatherbit", "status": "ok", "latency_ms": 0, "cache_age": 30, "temperature": -40.0
+        }));
+        assert_eq!(skipped.to_json("temperature", |t| json!(t)), json!({
+            "name": "apixu", "status": "skipped", "latency_ms": 1500
+        }));
+    }
+}
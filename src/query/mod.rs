@@ -0,0 +1,218 @@
+use units::{Units, TemperatureUnit, SpeedUnit, PressureUnit, DistanceUnit, PrecipitationUnit};
+use forecast::FORECAST_DAYS;
+use location::Location;
+use aggregation::Strategy;
+
+// Representation of a response body
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Format> {
+        match s {
+            "text" | "plain" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub location: Option<Location>,
+    pub format: Option<Format>,
+    pub units: Units,
+    pub days: usize,
+    // aggregation strategy, the configured one when not given
+    pub aggregation: Option<Strategy>
+}
+
+impl Query {
+    // Parses query strings like "q=tomsk&format=json&units=imperial&days=3&agg=median".
+    // Location is given by `lat` and `lon`, `postal_code` with optional `country`,
+    // or `q` with a name or "auto" for client's ip; a bare value without a key,
+    // as in "tomsk", is taken as `q`. Unit system is applied first, per quantity
+    // units override it. Unknown parameters are ignored
+    pub fn parse(raw: &str) -> Result<Query, String> {
+        let mut location = None;
+        let mut bare_location = None;
+        let (mut lat, mut lon) = (None, None);
+        let (mut postal_code, mut country) = (None, None);
+        let mut format = None;
+        let mut units = Units::metric();
+        let mut days = FORECAST_DAYS;
+        let mut aggregation = None;
+        let mut overrides: Vec<(String, String)> = vec![];
+
+        for part in raw.split('&').filter(|p| p.len() > 0) {
+            let (key, value) = match part.find('=') {
+                Some(idx) => (decode_component(&part[..idx])?, decode_component(&part[idx+1..])?),
+                None => {
+                    if bare_location.is_some() {
+                        return Err(format!("Unexpected parameter: {}", decode_component(part)?));
+                    }
+                    bare_location = Some(decode_component(part)?);
+                    continue;
+                }
+            };
+
+            match key.as_str() {
+                "q" => location = Some(value),
+                "lat" => lat = Some(value),
+                "lon" => lon = Some(value),
+                "postal_code" => postal_code = Some(value),
+                "country" => country = Some(value),
+                "format" => format = Some(Format::parse(&value).ok_or(format!("Unknown format: {}", value))?),
+                "units" => units = Units::system(&value).ok_or(format!("Unknown units: {}", value))?,
+                "days" => days = match value.parse::<usize>() {
+                    Ok(n) if n >= 1 && n <= FORECAST_DAYS => n,
+                    _ => return Err(format!("days should be from 1 to {}", FORECAST_DAYS))
+                },
+                "agg" => aggregation = Some(Strategy::parse(&value).ok_or(format!("Unknown aggregation: {}", value))?),
+                "temperature_unit" | "wind_unit" | "pressure_unit" | "visibility_unit" | "precipitation_unit" => overrides.push((key, value)),
+                _ => {}
+            }
+        }
+
+        for (key, value) in overrides {
+            let unknown = || format!("Unknown {}: {}", key, value);
+            match key.as_str() {
+                "temperature_unit" => units.temperature = TemperatureUnit::parse(&value).ok_or_else(unknown)?,
+                "wind_unit" => units.wind_speed = SpeedUnit::parse(&value).ok_or_else(unknown)?,
+                "pressure_unit" => units.pressure = PressureUnit::parse(&value).ok_or_else(unknown)?,
+                "visibility_unit" => units.visibility = DistanceUnit::parse(&value).ok_or_else(unknown)?,
+                _ => units.precipitation = PrecipitationUnit::parse(&value).ok_or_else(unknown)?
+            }
+        }
+
+        let location = match (lat, lon, postal_code) {
+            (Some(lat), Some(lon), _) => Some(Location::coordinates(&lat, &lon)?),
+            (Some(_), None, _) | (None, Some(_), _) => return Err(format!("Both lat and lon should be given")),
+            (None, None, Some(code)) => Some(Location::PostalCode { code: code.trim().to_owned(), country: country }),
+            (None, None, None) => match location.or(bare_location).map(|l| l.trim().to_owned()) {
+                Some(ref name) if name.to_lowercase() == "auto" => Some(Location::Auto),
+                Some(name) => if name.len() > 0 { Some(Location::Name(name)) } else { None },
+                None => None
+            }
+        };
+
+        Ok(Query {
+            location: location,
+            format: format,
+            units: units,
+            days: days,
+            aggregation: aggregation
+        })
+    }
+}
+
+// Decodes percent-encoded query component, "+" stands for a space
+pub fn decode_component(s: &str) -> Result<String, String> {
+    let bytes = s.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                // from_str_radix would take a sign like in "%+1"
+                let hex = s.get(idx+1..idx+3)
+                    .filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()))
+                    .and_then(|h| u8::from_str_radix(h, 16).ok());
+                match hex {
+                    Some(b) => decoded.push(b),
+                    None => return Err(format!("Malformed query: {}", s))
+                }
+                idx += 2;
+            },
+            b => decoded.push(b)
+        }
+        idx += 1;
+    }
+
+    String::from_utf8(decoded).map_err(|_| format!("Malformed query: {}", s))
+}
+
+// Percent-encodes a value to be placed into an upstream url query
+pub fn encode_component(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        b => format!("%{:02X}", b)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(location: &str) -> Query {
+        Query { location: Some(Location::Name(location.to_owned())), format: None, units: Units::metric(), days: FORECAST_DAYS, aggregation: None }
+    }
+
+    #[test]
+    fn it_parses_bare_location() {
+        assert_eq!(Query::parse("tomsk"), Ok(query("tomsk")));
+        assert_eq!(Query::parse("New%20York"), Ok(query("New York")));
+        assert_eq!(Query::parse("S%C3%A3o+Paulo"), Ok(query("São Paulo")));
+    }
+
+    #[test]
+    fn it_parses_location_parameter() {
+        assert_eq!(Query::parse("q=tomsk&days=3"), Ok(Query { days: 3, ..query("tomsk") }));
+        assert_eq!(Query::parse("q=perm%26key%3Dsecret"), Ok(query("perm&key=secret")));
+        assert_eq!(Query::parse("tomsk&key=secret"), Ok(query("tomsk")));
+    }
+
+    #[test]
+    fn it_parses_other_location_forms() {
+        assert_eq!(Query::parse("lat=56.5&lon=84.97").unwrap().location, Some(Location::Coordinates { lat: 56.5, lon: 84.97 }));
+        assert_eq!(Query::parse("postal_code=94040&country=us").unwrap().location, Some(Location::PostalCode {
+            code: "94040".to_owned(),
+            country: Some("us".to_owned())
+        }));
+        assert_eq!(Query::parse("q=auto").unwrap().location, Some(Location::Auto));
+        assert_eq!(Query::parse("format=json").unwrap().location, None);
+        assert_eq!(Query::parse("lat=56.5"), Err("Both lat and lon should be given".to_owned()));
+    }
+
+    #[test]
+    fn it_parses_format() {
+        assert_eq!(Query::parse("tomsk&format=json").unwrap().format, Some(Format::Json));
+        assert_eq!(Query::parse("format=text&q=perm").unwrap(), Query { format: Some(Format::Text), ..query("perm") });
+    }
+
+    #[test]
+    fn it_parses_aggregation() {
+        assert_eq!(Query::parse("tomsk&agg=median"), Ok(Query { aggregation: Some(Strategy::Median), ..query("tomsk") }));
+    }
+
+    #[test]
+    fn it_parses_units_with_overrides() {
+        let query = Query::parse("wind_unit=kmh&tomsk&units=imperial").unwrap();
+
+        assert_eq!(query.location, Some(Location::Name("tomsk".to_owned())));
+        assert_eq!(query.units, Units { wind_speed: SpeedUnit::KilometersPerHour, ..Units::imperial() });
+    }
+
+    #[test]
+    fn it_rejects_invalid_parameters() {
+        assert_eq!(Query::parse("tomsk&units=nautical"), Err("Unknown units: nautical".to_owned()));
+        assert_eq!(Query::parse("tomsk&temperature_unit=R"), Err("Unknown temperature_unit: R".to_owned()));
+        assert_eq!(Query::parse("tomsk&days=10"), Err("days should be from 1 to 5".to_owned()));
+        assert_eq!(Query::parse("tomsk&agg=mode"), Err("Unknown aggregation: mode".to_owned()));
+        assert_eq!(Query::parse("tomsk&perm"), Err("Unexpected parameter: perm".to_owned()));
+        assert_eq!(Query::parse("tomsk%2"), Err("Malformed query: tomsk%2".to_owned()));
+        assert_eq!(Query::parse("tomsk%+1"), Err("Malformed query: tomsk%+1".to_owned()));
+    }
+
+    #[test]
+    fn it_encodes_components() {
+        assert_eq!(encode_component("São Paulo"), "S%C3%A3o%20Paulo");
+        assert_eq!(encode_component("perm&key=secret"), "perm%26key%3Dsecret");
+        assert_eq!(encode_component("tomsk"), "tomsk");
+    }
+}
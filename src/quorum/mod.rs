@@ -0,0 +1,184 @@
+// Collects provider results without waiting for the slowest provider:
+// the response is built once enough providers answered or the deadline passed
+
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+
+use std::time::{Duration, Instant};
+
+use self::futures::{Async, Future, Poll};
+use self::tokio_core::reactor::{Handle, Timeout};
+
+use async_request::error::ApiError;
+use provider::ProviderResult;
+
+// `quorum` is the number of successful answers enough to respond,
+// None waits for every provider. Providers still running at `deadline`
+// are skipped, None waits for them as long as their requests take
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct QuorumConfig {
+    pub quorum: Option<usize>,
+    pub deadline: Option<Duration>
+}
+
+pub type ResultFuture<T> = Box<Future<Item = ProviderResult<T>, Error = hyper::Error>>;
+
+// Like `join_all`, but resolves early according to the config. Results keep
+// the order of requests, late providers are reported as `ApiError::Skipped`
+// and keep running in background, so their responses are still cached
+pub fn gather<T: 'static>(handle: &Handle, requests: Vec<(&'static str, ResultFuture<T>)>, config: &QuorumConfig) -> Box<Future<Item = Vec<ProviderResult<T>>, Error = hyper::Error>> {
+    let deadline = config.deadline.and_then(|d| Timeout::new(d, handle).ok());
+    let count = requests.len();
+
+    Box::new(Gather {
+        pending: requests.into_iter().map(Some).collect(),
+        results: (0..count).map(|_| None).collect(),
+        quorum: config.quorum.unwrap_or(count),
+        deadline: deadline,
+        started: Instant::now(),
+        handle: handle.clone()
+    })
+}
+
+struct Gather<T> {
+    pending: Vec<Option<(&'static str, ResultFuture<T>)>>,
+    results: Vec<Option<ProviderResult<T>>>,
+    quorum: usize,
+    deadline: Option<Timeout>,
+    started: Instant,
+    handle: Handle
+}
+
+impl<T: 'static> Gather<T> {
+    fn answered(&self) -> usize {
+        self.results.iter().filter(|r| r.as_ref().map(|r| r.result.is_ok()).unwrap_or(false)).count()
+    }
+
+    fn expired(&mut self) -> Result<bool, hyper::Error> {
+        match self.deadline {
+            Some(ref mut deadline) => Ok(deadline.poll()?.is_ready()),
+            None => Ok(false)
+        }
+    }
+}
+
+impl<T: 'static> Future for Gather<T> {
+    type Item = Vec<ProviderResult<T>>;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        for i in 0..self.pending.len() {
+            let ready = match self.pending[i] {
+                Some((_, ref mut request)) => match request.poll()? {
+                    Async::Ready(result) => Some(result),
+                    Async::NotReady => None
+                },
+                None => None
+            };
+
+            if let Some(result) = ready {
+                self.results[i] = Some(result);
+                self.pending[i] = None;
+            }
+        }
+
+        let finished = self.pending.iter().all(|p| p.is_none());
+        if !finished && self.answered() < self.quorum && !self.expired()? {
+            return Ok(Async::NotReady);
+        }
+
+        let latency = self.started.elapsed();
+        let mut results = vec![];
+        for (pending, result) in self.pending.drain(..).zip(self.results.drain(..)) {
+            match (pending, result) {
+                (_, Some(result)) => results.push(result),
+                (Some((name, request)), None) => {
+                    self.handle.spawn(request.then(|_| Ok(())));
+                    results.push(ProviderResult { name: name, result: Err(ApiError::Skipped), latency: latency, age: None });
+                },
+                (None, None) => {}
+            }
+        }
+
+        Ok(Async::Ready(results))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn answer(name: &'static str, value: f32) -> (&'static str, ResultFuture<f32>) {
+        (name, Box::new(futures::future::ok(ProviderResult { name: name, result: Ok(value), latency: Duration::from_millis(0), age: None })))
+    }
+
+    fn failure(name: &'static str) -> (&'static str, ResultFuture<f32>) {
+        (name, Box::new(futures::future::ok(ProviderResult { name: name, result: Err(ApiError::Other), latency: Duration::from_millis(0), age: None })))
+    }
+
+    fn never(name: &'static str) -> (&'static str, ResultFuture<f32>) {
+        (name, Box::new(futures::future::empty()))
+    }
+
+    fn outcome(results: Vec<ProviderResult<f32>>) -> Vec<(&'static str, Result<f32, ApiError>)> {
+        results.into_iter().map(|r| (r.name, r.result)).collect()
+    }
+
+    #[test]
+    fn it_responds_once_quorum_answered() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let handle = core.handle();
+        let config = QuorumConfig { quorum: Some(2), deadline: None };
+
+        let results = core.run(gather(&handle, vec![answer("owm", -14.0), never("apixu"), answer("weatherbit", -13.0)], &config)).unwrap();
+
+        assert_eq!(outcome(results), vec![("owm", Ok(-14.0)), ("apixu", Err(ApiError::Skipped)), ("weatherbit", Ok(-13.0))]);
+    }
+
+    #[test]
+    fn failures_dont_count_towards_quorum() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let handle = core.handle();
+        let config = QuorumConfig { quorum: Some(1), deadline: None };
+
+        let results = core.run(gather(&handle, vec![failure("owm"), answer("apixu", -12.0)], &config)).unwrap();
+
+        assert_eq!(outcome(results), vec![("owm", Err(ApiError::Other)), ("apixu", Ok(-12.0))]);
+    }
+
+    #[test]
+    fn it_skips_providers_late_for_deadline() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let handle = core.handle();
+        let config = QuorumConfig { quorum: None, deadline: Some(Duration::from_millis(50)) };
+
+        let results = core.run(gather(&handle, vec![answer("owm", -14.0), never("apixu")], &config)).unwrap();
+
+        assert_eq!(results[1].latency >= Duration::from_millis(50), true);
+        assert_eq!(outcome(results), vec![("owm", Ok(-14.0)), ("apixu", Err(ApiError::Skipped))]);
+    }
+
+    #[test]
+    fn late_providers_keep_running() {
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let handle = core.handle();
+        let config = QuorumConfig { quorum: Some(1), deadline: None };
+
+        let completed = Rc::new(Cell::new(false));
+        let flag = completed.clone();
+        let late = Timeout::new(Duration::from_millis(20), &handle).unwrap().map_err(hyper::Error::from).map(move |_| {
+            flag.set(true);
+            ProviderResult { name: "apixu", result: Ok(-12.0), latency: Duration::from_millis(20), age: None }
+        });
+
+        let results = core.run(gather(&handle, vec![answer("owm", -14.0), ("apixu", Box::new(late))], &config)).unwrap();
+        assert_eq!(outcome(results), vec![("owm", Ok(-14.0)), ("apixu", Err(ApiError::Skipped))]);
+        assert_eq!(completed.get(), false);
+
+        core.run(Timeout::new(Duration::from_millis(50), &handle).unwrap()).unwrap();
+        assert_eq!(completed.get(), true);
+    }
+}
@@ -0,0 +1,357 @@
+// On-disk snapshot of the response cache, reloaded on startup so that
+// a restarted instance doesn't query providers for every location at once
+
+extern crate futures;
+extern crate tokio_core;
+extern crate serde_json;
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread;
+
+use self::futures::{Future, Stream};
+use self::serde_json::Value;
+use self::tokio_core::reactor::{Handle, Interval};
+
+use cache::ResponseCache;
+use config::CacheConfig;
+use observation::{CurrentObservation, json_f32, json_string};
+use forecast::{Forecast, ForecastDate, DailyForecast};
+use geocoding::CanonicalLocation;
+use location::Coordinates;
+
+// Values the cache is able to write to disk and read back
+pub trait Stored: Sized {
+    fn to_stored(&self) -> Value;
+
+    fn from_stored(v: &Value) -> Option<Self>;
+}
+
+// Cached value along with its key and unix time it was stored at
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub kind: String,
+    pub provider: String,
+    pub location: String,
+    pub stored_at: u64,
+    pub value: Value
+}
+
+impl Record {
+    fn to_json(&self) -> Value {
+        json!({
+            "kind": self.kind,
+            "provider": self.provider,
+            "location": self.location,
+            "stored_at": self.stored_at,
+            "value": self.value
+        })
+    }
+
+    fn from_json(v: &Value) -> Option<Record> {
+        Some(Record {
+            kind: json_string(&v["kind"])?,
+            provider: json_string(&v["provider"])?,
+            location: json_string(&v["location"])?,
+            stored_at: v["stored_at"].as_u64()?,
+            value: v["value"].clone()
+        })
+    }
+}
+
+// Reads records saved by `save`, a missing file is an empty snapshot
+// and lines which fail to parse are skipped
+pub fn load(path: &Path) -> io::Result<Vec<Record>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e)
+    };
+
+    let mut records = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if let Some(record) = serde_json::from_str::<Value>(&line).ok().as_ref().and_then(Record::from_json) {
+            records.push(record);
+        }
+    }
+
+    Ok(records)
+}
+
+// Writes records as json lines, newest first while they fit into `max_bytes`,
+// older ones are evicted. The snapshot is written aside and renamed over
+// the previous one, so a crash never leaves a torn file. Returns number of records written
+pub fn save(path: &Path, records: &[Record], max_bytes: u64) -> io::Result<usize> {
+    let mut newest_first: Vec<&Record> = records.iter().collect();
+    newest_first.sort_by(|a, b| b.stored_at.cmp(&a.stored_at));
+
+    let mut contents = String::new();
+    let mut written = 0;
+    for record in newest_first {
+        let line = record.to_json().to_string() + "\n";
+        if (contents.len() + line.len()) as u64 > max_bytes {
+            break;
+        }
+        contents.push_str(&line);
+        written += 1;
+    }
+
+    let tmp = temporary_path(path);
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+
+    Ok(written)
+}
+
+// Saves snapshots to `path` on a thread of its own, so that connections
+// don't wait for the disk. Snapshots taken while the previous one
+// is still being written are dropped
+#[derive(Clone)]
+pub struct Writer {
+    snapshots: SyncSender<Vec<Record>>
+}
+
+impl Writer {
+    // `what` names the snapshots in log messages
+    pub fn new(path: PathBuf, max_bytes: u64, what: &'static str) -> io::Result<Writer> {
+        let (snapshots, received) = mpsc::sync_channel::<Vec<Record>>(1);
+
+        thread::Builder::new().name(format!("{} snapshots", what)).spawn(move || {
+            for records in received {
+                if let Err(e) = save(&path, &records, max_bytes) {
+                    println!("Failed to save {} to {}: {}", what, path.display(), e);
+                }
+            }
+        })?;
+
+        Ok(Writer { snapshots: snapshots })
+    }
+
+    // Returns whether the snapshot was taken on for saving
+    pub fn save(&self, records: Vec<Record>) -> bool {
+        self.snapshots.try_send(records).is_ok()
+    }
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+// Restores the cache from `config.path` and schedules its snapshots,
+// does nothing when no path is configured
+pub fn persist(handle: &Handle, cache: &ResponseCache, config: &CacheConfig) {
+    let path = match config.path {
+        Some(ref path) => path.clone(),
+        None => return
+    };
+
+    match load(&path) {
+        Ok(records) => {
+            let restored = cache.restore(&records);
+            println!("Restored {} cached responses from {}", restored, path.display());
+        },
+        Err(e) => println!("Failed to load cache from {}: {}", path.display(), e)
+    }
+
+    let writer = match Writer::new(path.clone(), config.max_bytes, "cache") {
+        Ok(writer) => writer,
+        Err(e) => return println!("Failed to start saving cache to {}: {}", path.display(), e)
+    };
+
+    let cache = cache.clone();
+    let snapshots = Interval::new(config.flush_interval, handle).unwrap().for_each(move |_| {
+        writer.save(cache.records());
+        Ok(())
+    });
+
+    handle.spawn(snapshots.map_err(|e| println!("cache snapshots stopped: {:?}", e)));
+}
+
+fn coordinates_json(coordinates: Option<Coordinates>) -> Value {
+    match coordinates {
+        Some(c) => json!({ "lat": c.lat, "lon": c.lon }),
+        None => Value::Null
+    }
+}
+
+fn coordinates_from_json(v: &Value) -> Option<Coordinates> {
+    Coordinates::from_json(&v["lat"], &v["lon"])
+}
+
+impl Stored for CurrentObservation {
+    fn to_stored(&self) -> Value {
+        let mut v = self.to_json();
+        v["coordinates"] = coordinates_json(self.coordinates);
+        v
+    }
+
+    fn from_stored(v: &Value) -> Option<CurrentObservation> {
+        Some(CurrentObservation {
+            temperature: json_f32(&v["temperature"])?,
+            humidity: json_f32(&v["humidity"]),
+            pressure: json_f32(&v["pressure"]),
+            wind_speed: json_f32(&v["wind_speed"]),
+            wind_direction: json_f32(&v["wind_direction"]),
+            cloud_cover: json_f32(&v["cloud_cover"]),
+            visibility: json_f32(&v["visibility"]),
+            precipitation: json_f32(&v["precipitation"]),
+            condition: json_string(&v["condition"]),
+            observed_at: v["observed_at"].as_i64(),
+            coordinates: coordinates_from_json(&v["coordinates"])
+        })
+    }
+}
+
+impl Stored for Forecast {
+    fn to_stored(&self) -> Value {
+        let mut v = self.to_json();
+        v["coordinates"] = coordinates_json(self.coordinates);
+        v["utc_offset"] = json!(self.utc_offset);
+        v
+    }
+
+    fn from_stored(v: &Value) -> Option<Forecast> {
+        let days = v["days"].as_array()?.iter().filter_map(|d| {
+            let date = d["date"].as_str().and_then(ForecastDate::parse)?;
+            Some((date, DailyForecast {
+                temperature: json_f32(&d["temperature"]),
+                min_temperature: json_f32(&d["min_temperature"]),
+                max_temperature: json_f32(&d["max_temperature"]),
+                precipitation: json_f32(&d["precipitation"]),
+                precipitation_probability: json_f32(&d["precipitation_probability"]),
+                wind_speed: json_f32(&d["wind_speed"]),
+                condition: json_string(&d["condition"])
+            }))
+        }).collect();
+
+        Some(Forecast {
+            timezone: json_string(&v["timezone"]),
+            utc_offset: v["utc_offset"].as_i64(),
+            coordinates: coordinates_from_json(&v["coordinates"]),
+            days: days
+        })
+    }
+}
+
+impl Stored for CanonicalLocation {
+    fn to_stored(&self) -> Value {
+        self.to_json()
+    }
+
+    fn from_stored(v: &Value) -> Option<CanonicalLocation> {
+        Some(CanonicalLocation {
+            name: json_string(&v["name"])?,
+            region: json_string(&v["region"]),
+            country: json_string(&v["country"]),
+            coordinates: coordinates_from_json(v)?,
+            timezone: json_string(&v["timezone"])
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("weather-store-{}-{}", process::id(), name))
+    }
+
+    fn record(location: &str, stored_at: u64) -> Record {
+        Record {
+            kind: "current".to_owned(),
+            provider: "owm".to_owned(),
+            location: location.to_owned(),
+            stored_at: stored_at,
+            value: json!({ "temperature": -14.0 })
+        }
+    }
+
+    #[test]
+    fn it_saves_and_loads_records() {
+        let path = temp_path("roundtrip");
+        let records = vec![record("name:tomsk", 1517661000), record("name:perm", 1517661028)];
+
+        assert_eq!(save(&path, &records, 1024).unwrap(), 2);
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, vec![record("name:perm", 1517661028), record("name:tomsk", 1517661000)]);
+    }
+
+    #[test]
+    fn writer_saves_in_background() {
+        let path = temp_path("writer");
+        let writer = Writer::new(path.clone(), 1024, "cache").unwrap();
+
+        assert!(writer.save(vec![record("name:tomsk", 1517661000)]));
+        let mut loaded = vec![];
+        for _ in 0..100 {
+            loaded = load(&path).unwrap();
+            if !loaded.is_empty() {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, vec![record("name:tomsk", 1517661000)]);
+    }
+
+    #[test]
+    fn it_evicts_oldest_records_over_size_limit() {
+        let path = temp_path("limit");
+        let records = vec![record("name:tomsk", 1517661000), record("name:perm", 1517661028)];
+        let line_length = record("name:perm", 1517661028).to_json().to_string().len() as u64 + 1;
+
+        assert_eq!(save(&path, &records, line_length).unwrap(), 1);
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, vec![record("name:perm", 1517661028)]);
+    }
+
+    #[test]
+    fn it_treats_missing_file_as_empty() {
+        assert_eq!(load(&temp_path("missing")).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn values_survive_storing() {
+        let observation = CurrentObservation {
+            humidity: Some(78.0),
+            condition: Some("Overcast".to_owned()),
+            observed_at: Some(1517661028),
+            coordinates: Some(Coordinates::new(56.5, 84.97)),
+            ..CurrentObservation::new(-14.0)
+        };
+        assert_eq!(CurrentObservation::from_stored(&observation.to_stored()), Some(observation));
+
+        let forecast = Forecast {
+            timezone: Some("Asia/Yekaterinburg".to_owned()),
+            utc_offset: Some(5 * 3600),
+            coordinates: None,
+            days: vec![(ForecastDate::new(2018, 2, 3), DailyForecast { temperature: Some(-9.8), ..Default::default() })].into_iter().collect()
+        };
+        assert_eq!(Forecast::from_stored(&forecast.to_stored()), Some(forecast));
+
+        let location = CanonicalLocation {
+            name: "Perm".to_owned(),
+            region: None,
+            country: Some("Russia".to_owned()),
+            coordinates: Coordinates::new(58.0, 56.25),
+            timezone: Some("Asia/Yekaterinburg".to_owned())
+        };
+        assert_eq!(CanonicalLocation::from_stored(&location.to_stored()), Some(location));
+    }
+}
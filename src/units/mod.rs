@@ -0,0 +1,284 @@
+extern crate serde_json;
+
+use self::serde_json::Value;
+
+// Providers are queried in metric units, values are converted
+// to the requested ones only after aggregation
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+    Kelvin
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpeedUnit {
+    MetersPerSecond,
+    KilometersPerHour,
+    MilesPerHour,
+    Knots
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PressureUnit {
+    Hectopascal,
+    InchesOfMercury,
+    MillimetersOfMercury
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DistanceUnit {
+    Kilometers,
+    Miles
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrecipitationUnit {
+    Millimeters,
+    Inches
+}
+
+impl TemperatureUnit {
+    pub fn parse(s: &str) -> Option<TemperatureUnit> {
+        match s.to_lowercase().as_str() {
+            "c" | "celsius" => Some(TemperatureUnit::Celsius),
+            "f" | "fahrenheit" => Some(TemperatureUnit::Fahrenheit),
+            "k" | "kelvin" => Some(TemperatureUnit::Kelvin),
+            _ => None
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            TemperatureUnit::Celsius => "°C",
+            TemperatureUnit::Fahrenheit => "°F",
+            TemperatureUnit::Kelvin => "K"
+        }
+    }
+
+    // Converts from °C
+    pub fn convert(&self, celsius: f32) -> f32 {
+        match *self {
+            TemperatureUnit::Celsius => celsius,
+            TemperatureUnit::Fahrenheit => celsius * 9.0 / 5.0 + 32.0,
+            TemperatureUnit::Kelvin => celsius + 273.15
+        }
+    }
+}
+
+impl SpeedUnit {
+    pub fn parse(s: &str) -> Option<SpeedUnit> {
+        match s.to_lowercase().as_str() {
+            "ms" | "m/s" => Some(SpeedUnit::MetersPerSecond),
+            "kmh" | "km/h" | "kph" => Some(SpeedUnit::KilometersPerHour),
+            "mph" => Some(SpeedUnit::MilesPerHour),
+            "kn" | "knots" => Some(SpeedUnit::Knots),
+            _ => None
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            SpeedUnit::MetersPerSecond => "m/s",
+            SpeedUnit::KilometersPerHour => "km/h",
+            SpeedUnit::MilesPerHour => "mph",
+            SpeedUnit::Knots => "kn"
+        }
+    }
+
+    // Converts from m/s
+    pub fn convert(&self, mps: f32) -> f32 {
+        match *self {
+            SpeedUnit::MetersPerSecond => mps,
+            SpeedUnit::KilometersPerHour => mps * 3.6,
+            SpeedUnit::MilesPerHour => mps * 2.236_936,
+            SpeedUnit::Knots => mps * 1.943_844
+        }
+    }
+}
+
+impl PressureUnit {
+    pub fn parse(s: &str) -> Option<PressureUnit> {
+        match s.to_lowercase().as_str() {
+            "hpa" | "mb" => Some(PressureUnit::Hectopascal),
+            "inhg" => Some(PressureUnit::InchesOfMercury),
+            "mmhg" => Some(PressureUnit::MillimetersOfMercury),
+            _ => None
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            PressureUnit::Hectopascal => "hPa",
+            PressureUnit::InchesOfMercury => "inHg",
+            PressureUnit::MillimetersOfMercury => "mmHg"
+        }
+    }
+
+    // Converts from hPa
+    pub fn convert(&self, hpa: f32) -> f32 {
+        match *self {
+            PressureUnit::Hectopascal => hpa,
+            PressureUnit::InchesOfMercury => hpa * 0.029_53,
+            PressureUnit::MillimetersOfMercury => hpa * 0.750_062
+        }
+    }
+
+    pub fn precision(&self) -> usize {
+        match *self {
+            PressureUnit::InchesOfMercury => 2,
+            _ => 1
+        }
+    }
+}
+
+impl DistanceUnit {
+    pub fn parse(s: &str) -> Option<DistanceUnit> {
+        match s.to_lowercase().as_str() {
+            "km" => Some(DistanceUnit::Kilometers),
+            "mi" | "miles" => Some(DistanceUnit::Miles),
+            _ => None
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            DistanceUnit::Kilometers => "km",
+            DistanceUnit::Miles => "mi"
+        }
+    }
+
+    // Converts from km
+    pub fn convert(&self, km: f32) -> f32 {
+        match *self {
+            DistanceUnit::Kilometers => km,
+            DistanceUnit::Miles => km * 0.621_371
+        }
+    }
+}
+
+impl PrecipitationUnit {
+    pub fn parse(s: &str) -> Option<PrecipitationUnit> {
+        match s.to_lowercase().as_str() {
+            "mm" => Some(PrecipitationUnit::Millimeters),
+            "in" | "inches" => Some(PrecipitationUnit::Inches),
+            _ => None
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            PrecipitationUnit::Millimeters => "mm",
+            PrecipitationUnit::Inches => "in"
+        }
+    }
+
+    // Converts from mm
+    pub fn convert(&self, mm: f32) -> f32 {
+        match *self {
+            PrecipitationUnit::Millimeters => mm,
+            PrecipitationUnit::Inches => mm / 25.4
+        }
+    }
+
+    pub fn precision(&self) -> usize {
+        match *self {
+            PrecipitationUnit::Inches => 2,
+            _ => 1
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Units {
+    pub temperature: TemperatureUnit,
+    pub wind_speed: SpeedUnit,
+    pub pressure: PressureUnit,
+    pub visibility: DistanceUnit,
+    pub precipitation: PrecipitationUnit
+}
+
+impl Default for Units {
+    fn default() -> Units {
+        Units::metric()
+    }
+}
+
+impl Units {
+    pub fn metric() -> Units {
+        Units {
+            temperature: TemperatureUnit::Celsius,
+            wind_speed: SpeedUnit::MetersPerSecond,
+            pressure: PressureUnit::Hectopascal,
+            visibility: DistanceUnit::Kilometers,
+            precipitation: PrecipitationUnit::Millimeters
+        }
+    }
+
+    pub fn imperial() -> Units {
+        Units {
+            temperature: TemperatureUnit::Fahrenheit,
+            wind_speed: SpeedUnit::MilesPerHour,
+            pressure: PressureUnit::InchesOfMercury,
+            visibility: DistanceUnit::Miles,
+            precipitation: PrecipitationUnit::Inches
+        }
+    }
+
+    // Same as metric but with temperatures in Kelvin, as in owm "standard" units
+    pub fn standard() -> Units {
+        Units { temperature: TemperatureUnit::Kelvin, ..Units::metric() }
+    }
+
+    pub fn system(s: &str) -> Option<Units> {
+        match s.to_lowercase().as_str() {
+            "metric" => Some(Units::metric()),
+            "imperial" => Some(Units::imperial()),
+            "standard" => Some(Units::standard()),
+            _ => None
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "temperature": self.temperature.symbol(),
+            "humidity": "%",
+            "pressure": self.pressure.symbol(),
+            "wind_speed": self.wind_speed.symbol(),
+            "wind_direction": "°",
+            "cloud_cover": "%",
+            "visibility": self.visibility.symbol(),
+            "precipitation": self.precipitation.symbol(),
+            "precipitation_probability": "%"
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_converts_temperatures() {
+        assert_eq!(TemperatureUnit::Fahrenheit.convert(-40.0), -40.0);
+        assert_eq!(TemperatureUnit::Fahrenheit.convert(100.0), 212.0);
+        assert_eq!(TemperatureUnit::Kelvin.convert(0.0), 273.15);
+    }
+
+    #[test]
+    fn it_converts_other_quantities() {
+        assert_eq!(SpeedUnit::KilometersPerHour.convert(10.0), 36.0);
+        assert_eq!(PrecipitationUnit::Inches.convert(25.4), 1.0);
+        assert!((PressureUnit::InchesOfMercury.convert(1013.25) - 29.92).abs() < 0.01);
+        assert!((DistanceUnit::Miles.convert(10.0) - 6.21).abs() < 0.01);
+    }
+
+    #[test]
+    fn it_parses_unit_systems() {
+        assert_eq!(Units::system("Imperial"), Some(Units::imperial()));
+        assert_eq!(Units::system("standard").map(|u| u.temperature), Some(TemperatureUnit::Kelvin));
+        assert_eq!(Units::system("nautical"), None);
+        assert_eq!(SpeedUnit::parse("km/h"), Some(SpeedUnit::KilometersPerHour));
+    }
+}
@@ -2,88 +2,143 @@
 
 extern crate futures;
 extern crate hyper;
-extern crate tokio_core;
 extern crate serde_json;
-extern crate itertools;
 
 use self::serde_json::Value;
-use std::io;
-use self::futures::{Future};
 
-use self::tokio_core::reactor::{Handle};
-
-use async_request::async_json_request;
-use async_request::Result;
+use async_request::{async_api_request, ApiFuture, AsyncJsonResponse};
 use async_request::error::ApiError;
+use async_request::client::HttpClient;
+use keys::KeyRing;
+use provider::{WeatherProvider, Capabilities, unsupported_location};
+use query::encode_component;
+use location::{Location, Coordinates};
+use observation::{CurrentObservation, json_f32, json_string};
+use forecast::{Forecast, ForecastDate, DailyForecast};
 
-use self::itertools::EitherOrBoth::{Both};
-use self::itertools::Itertools;
+#[cfg(not(test))]
+const API_ROOT: &'static str = "https://api.weatherbit.io/v2.0";
 
+pub struct Weatherbit {
+    keys: KeyRing
+}
 
-#[cfg(not(test))]
-const API_ROOT: &'static str = "http://api.weatherbit.io/v2.0";
-
-pub fn current(handle: &Handle, q: &str, api_key: &str) -> Box<Future<Item = Result<f32>, Error = hyper::Error>> {
-    let url = format!("{api_root}/current?key={key}&city={loc}", loc=q, key=api_key, api_root=API_ROOT);
-
-    let resp = async_json_request(handle, &url).and_then(|s| {
-        if s.status == hyper::StatusCode::NoContent {
-            return Ok(Err(ApiError::LocationNotFound));
-        }
-
-        if s.body.is_none() {
-            return Ok(Err(ApiError::Other));
-        }
-
-        let body = s.body.unwrap();
-        let json_temp: Value = body["data"][0]["temp"].clone();
-        let temp = serde_json::from_value::<f32>(json_temp).map_err(|e|
-            io::Error::new(
-                io::ErrorKind::Other,
-                e
-            )
-        )?;
-        Ok(Ok(temp))
-    }).or_else(|_s|
-        Ok(Err(ApiError::Other))
-    );
-
-    Box::new(resp)
+impl Weatherbit {
+    pub fn new(keys: KeyRing) -> Weatherbit {
+        Weatherbit { keys: keys }
+    }
 }
 
-pub fn forecast(handle: &Handle, q: &str, api_key: &str) -> Box<Future<Item = Result<Vec<Option<f32>>>, Error = hyper::Error>> {
-    let url = format!("{api_root}/forecast/daily?key={key}&city={loc}&days=5", loc=q, key=api_key, api_root=API_ROOT);
-
-    let resp = async_json_request(handle, &url).and_then(|s| {
-        if s.status == hyper::StatusCode::NoContent {
-            return Ok(Err(ApiError::LocationNotFound));
-        }
-
-        if s.body.is_none() {
-            return Ok(Err(ApiError::Other));
-        }
-
-        let body = s.body.unwrap();
-        let fcast: Value = body["data"].clone();
-        let json_temps: Vec<Value> = serde_json::from_value(fcast).map_err(|e|
-            io::Error::new(
-                io::ErrorKind::Other,
-                e
-            )
-        )?;
-        let temps: Vec<Option<f32>> = json_temps.into_iter().map(|v|
-            serde_json::from_value::<f32>(v["temp"].clone()).ok()
-        ).zip_longest(0..5).map(|v| match v {
-            Both(Some(t), _) => Some(t),
-            _ => None
+impl WeatherProvider for Weatherbit {
+    fn name(&self) -> &'static str {
+        "weatherbit"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { current: true, forecast: true }
+    }
+
+    fn supports(&self, location: &Location) -> bool {
+        location_params(location).is_some()
+    }
+
+    fn current(&self, client: &HttpClient, location: &Location) -> ApiFuture<CurrentObservation> {
+        current(client, location, &self.keys)
+    }
+
+    fn forecast(&self, client: &HttpClient, location: &Location) -> ApiFuture<Forecast> {
+        forecast(client, location, &self.keys)
+    }
+}
+
+pub fn current(client: &HttpClient, location: &Location, keys: &KeyRing) -> ApiFuture<CurrentObservation> {
+    let loc = match location_params(location) {
+        Some(params) => params,
+        None => return unsupported_location()
+    };
+    let url = move |key: &str| format!("{api_root}/current?key={key}&{loc}", loc=loc, key=encode_component(key), api_root=API_ROOT);
+
+    async_api_request(client, keys, url, api_error, |body| {
+        let data = &body["data"][0];
+        let temp = serde_json::from_value::<f32>(data["temp"].clone())?;
+
+        Ok(CurrentObservation {
+            temperature: temp,
+            humidity: json_f32(&data["rh"]),
+            // sea level pressure, as reported by owm and apixu
+            pressure: json_f32(&data["slp"]),
+            wind_speed: json_f32(&data["wind_spd"]),
+            wind_direction: json_f32(&data["wind_dir"]),
+            cloud_cover: json_f32(&data["clouds"]),
+            visibility: json_f32(&data["vis"]),
+            precipitation: json_f32(&data["precip"]),
+            condition: json_string(&data["weather"]["description"]),
+            observed_at: data["ts"].as_i64(),
+            coordinates: Coordinates::from_json(&data["lat"], &data["lon"])
+        })
+    })
+}
+
+pub fn forecast(client: &HttpClient, location: &Location, keys: &KeyRing) -> ApiFuture<Forecast> {
+    let loc = match location_params(location) {
+        Some(params) => params,
+        None => return unsupported_location()
+    };
+    let url = move |key: &str| format!("{api_root}/forecast/daily?key={key}&{loc}&days=5", loc=loc, key=encode_component(key), api_root=API_ROOT);
+
+    async_api_request(client, keys, url, api_error, |body| {
+        let json_days: Vec<Value> = serde_json::from_value(body["data"].clone())?;
+        let days = json_days.into_iter().filter_map(|v| {
+            let date = v["datetime"].as_str().and_then(ForecastDate::parse)?;
+            Some((date, DailyForecast {
+                temperature: json_f32(&v["temp"]),
+                min_temperature: json_f32(&v["min_temp"]),
+                max_temperature: json_f32(&v["max_temp"]),
+                precipitation: json_f32(&v["precip"]),
+                precipitation_probability: json_f32(&v["pop"]),
+                wind_speed: json_f32(&v["wind_spd"]),
+                condition: json_string(&v["weather"]["description"])
+            }))
         }).collect();
 
-        Ok(Ok(temps))
-    }).or_else(|_s|
-        Ok(Err(ApiError::Other))
-    );
+        // dates are already local to the location
+        Ok(Forecast {
+            timezone: json_string(&body["timezone"]),
+            // only the name of the timezone is reported
+            utc_offset: None,
+            // reported as strings
+            coordinates: Coordinates::from_json(&body["lat"], &body["lon"]),
+            days: days
+        })
+    })
+}
 
-    Box::new(resp)
+// Unknown places are answered with an empty 204 response. Running out of daily
+// calls is a 429 like exceeding the rate, told apart by the message
+fn api_error(response: &AsyncJsonResponse) -> Option<ApiError> {
+    let message = response.body.as_ref()
+        .and_then(|b| b["status_message"].as_str().or_else(|| b["error"].as_str()))
+        .unwrap_or("")
+        .to_lowercase();
+
+    match response.status {
+        hyper::StatusCode::NoContent => Some(ApiError::LocationNotFound),
+        hyper::StatusCode::Forbidden if message.contains("key") => Some(ApiError::InvalidApiKey),
+        hyper::StatusCode::TooManyRequests if message.contains("per day") => Some(ApiError::QuotaExceeded),
+        _ => None
+    }
+}
+
+// Location as weatherbit query parameters
+fn location_params(location: &Location) -> Option<String> {
+    match *location {
+        Location::Name(ref name) => Some(format!("city={}", encode_component(name))),
+        Location::Coordinates { lat, lon } => Some(format!("lat={}&lon={}", lat, lon)),
+        Location::PostalCode { ref code, country: Some(ref country) } => Some(format!("postal_code={}&country={}", encode_component(code), encode_component(country))),
+        Location::PostalCode { ref code, country: None } => Some(format!("postal_code={}", encode_component(code))),
+        Location::Ip(ref ip) => Some(format!("ip={}", encode_component(&ip.to_string()))),
+        Location::Auto => None
+    }
 }
 
 #[cfg(test)]
@@ -95,7 +150,13 @@ const API_ROOT: &'static str = mockito::SERVER_URL;
 #[cfg(test)]
 mod tests {
     use super::*;
+    use self::futures::Future;
     use self::mockito::{mock, Matcher};
+    use keys::KeysConfig;
+
+    fn key(key: &str) -> KeyRing {
+        KeyRing::new(&[key.to_owned()], KeysConfig::default())
+    }
 
     #[test]
     fn current_performs_request_to_api() {
@@ -106,14 +167,46 @@ mod tests {
             .create();
 
         let mut core = tokio_core::reactor::Core::new().unwrap();
-        let handle = core.handle();
-        let work = current(&handle, "Ufa", "");
+        let client = HttpClient::new(&core.handle());
+        let work = current(&client, &Location::Name("Ufa".to_owned()), &key(""));
         let r = core.run(work);
 
-        assert_eq!(r.unwrap(), Ok(-7.0));
+        assert_eq!(r.unwrap(), Ok(CurrentObservation {
+            temperature: -7.0,
+            humidity: Some(92.0),
+            pressure: Some(1029.0),
+            wind_speed: Some(2.0),
+            wind_direction: Some(160.0),
+            cloud_cover: Some(75.0),
+            visibility: Some(0.5),
+            precipitation: None,
+            condition: Some("Fog".to_owned()),
+            observed_at: Some(1517661000),
+            coordinates: Some(Coordinates::new(54.74306, 55.96779))
+        }));
         m1.assert();
     }
 
+    #[test]
+    fn current_queries_by_coordinates_and_postal_code() {
+        let m1 = mock("GET", Matcher::Regex(r#"^/current\?key=.*&lat=54.74&lon=55.97$"#.to_string()))
+            .with_status(204)
+            .create();
+        let m2 = mock("GET", Matcher::Regex(r#"^/current\?key=.*&postal_code=450000&country=RU$"#.to_string()))
+            .with_status(204)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let by_coordinates = current(&client, &Location::Coordinates { lat: 54.74, lon: 55.97 }, &key(""));
+        let by_postal_code = current(&client, &Location::PostalCode { code: "450000".to_owned(), country: Some("RU".to_owned()) }, &key(""));
+        let r = core.run(by_coordinates.join(by_postal_code));
+
+        assert_eq!(r.unwrap(), (Err(ApiError::LocationNotFound), Err(ApiError::LocationNotFound)));
+        m1.assert();
+        m2.assert();
+    }
+
     #[test]
     fn current_handles_wrong_cities() {
         let m2 = mock("GET", Matcher::Regex(r#"^/current.*new-ork.*"#.to_string()))
@@ -121,8 +214,8 @@ mod tests {
             .with_header("content-type", "application/json")
             .create();
         let mut core = tokio_core::reactor::Core::new().unwrap();
-        let handle = core.handle();
-        let work = current(&handle, "new-ork", "");
+        let client = HttpClient::new(&core.handle());
+        let work = current(&client, &Location::Name("new-ork".to_owned()), &key(""));
         let r = core.run(work);
 
         assert_eq!(r.unwrap(), Err(ApiError::LocationNotFound));
@@ -138,11 +231,31 @@ mod tests {
             .create();
 
         let mut core = tokio_core::reactor::Core::new().unwrap();
-        let handle = core.handle();
-        let work = forecast(&handle, "Ekaterinburg", "");
+        let client = HttpClient::new(&core.handle());
+        let work = forecast(&client, &Location::Name("Ekaterinburg".to_owned()), &key(""));
         let r = core.run(work);
 
-        assert_eq!(r.unwrap(), Ok(vec![Some(-9.0), Some(-11.0), Some(-9.0), Some(-6.0), Some(-7.0)]));
+        let forecast = r.unwrap().unwrap();
+        assert_eq!(forecast.timezone, Some("Asia/Yekaterinburg".to_owned()));
+        assert_eq!(forecast.coordinates, Some(Coordinates::new(56.8519, 60.6122)));
+
+        let temps: Vec<(String, Option<f32>)> = forecast.days.iter().map(|(date, d)| (date.to_string(), d.temperature)).collect();
+        assert_eq!(temps, vec![
+            ("2018-02-03".to_owned(), Some(-9.0)),
+            ("2018-02-04".to_owned(), Some(-11.0)),
+            ("2018-02-05".to_owned(), Some(-9.0)),
+            ("2018-02-06".to_owned(), Some(-6.0)),
+            ("2018-02-07".to_owned(), Some(-7.0))
+        ]);
+        assert_eq!(forecast.days[&ForecastDate::new(2018, 2, 3)], DailyForecast {
+            temperature: Some(-9.0),
+            min_temperature: Some(-14.0),
+            max_temperature: Some(-6.8),
+            precipitation: Some(0.0),
+            precipitation_probability: Some(0.0),
+            wind_speed: Some(0.0),
+            condition: Some("Scattered clouds".to_owned())
+        });
         m.assert();
     }
 
@@ -153,8 +266,8 @@ mod tests {
             .create();
 
         let mut core = tokio_core::reactor::Core::new().unwrap();
-        let handle = core.handle();
-        let work = forecast(&handle, "new-ork", "");
+        let client = HttpClient::new(&core.handle());
+        let work = forecast(&client, &Location::Name("new-ork".to_owned()), &key(""));
         let r = core.run(work);
 
         assert_eq!(r.unwrap(), Err(ApiError::LocationNotFound));
@@ -162,7 +275,7 @@ mod tests {
     }
 
     #[test]
-    fn forecast_fills_values_upto_five_with_none() {
+    fn forecast_keeps_only_reported_days() {
         let m = mock("GET", Matcher::Regex(r#"^/forecast/daily.*Ekaterinburg.*"#.to_string()))
             .with_status(200)
             .with_header("content-type", "application/json")
@@ -170,11 +283,38 @@ mod tests {
             .create();
 
         let mut core = tokio_core::reactor::Core::new().unwrap();
-        let handle = core.handle();
-        let work = forecast(&handle, "Ekaterinburg", "");
+        let client = HttpClient::new(&core.handle());
+        let work = forecast(&client, &Location::Name("Ekaterinburg".to_owned()), &key(""));
         let r = core.run(work);
 
-        assert_eq!(r.unwrap(), Ok(vec![Some(-9.0), Some(-11.0), Some(-9.0), None, None]));
+        let forecast = r.unwrap().unwrap();
+        let temps: Vec<(String, Option<f32>)> = forecast.days.iter().map(|(date, d)| (date.to_string(), d.temperature)).collect();
+        assert_eq!(temps, vec![
+            ("2018-02-03".to_owned(), Some(-9.0)),
+            ("2018-02-04".to_owned(), Some(-11.0)),
+            ("2018-02-05".to_owned(), Some(-9.0))
+        ]);
         m.assert();
     }
+
+    #[test]
+    fn it_tells_quota_from_invalid_key() {
+        let m1 = mock("GET", Matcher::Regex(r#"^/current\?key=used-up.*"#.to_string()))
+            .with_status(429)
+            .with_header("retry-after", "3600")
+            .with_body(r#"{"status_code":429,"status_message":"Your API key has reached calls per day allowed."}"#)
+            .create();
+        let m2 = mock("GET", Matcher::Regex(r#"^/current\?key=inactive.*"#.to_string()))
+            .with_status(403)
+            .with_body(r#"{"error":"API key not valid, or not yet activated."}"#)
+            .create();
+
+        let mut core = tokio_core::reactor::Core::new().unwrap();
+        let client = HttpClient::new(&core.handle());
+        let tomsk = Location::Name("tomsk".to_owned());
+        assert_eq!(core.run(current(&client, &tomsk, &key("used-up"))).unwrap(), Err(ApiError::QuotaExceeded));
+        assert_eq!(core.run(current(&client, &tomsk, &key("inactive"))).unwrap(), Err(ApiError::InvalidApiKey));
+        m1.assert();
+        m2.assert();
+    }
 }
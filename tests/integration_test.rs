@@ -6,15 +6,17 @@ extern crate tokio_core;
 use futures::Future;
 use std::env::var;
 use weather::async_request::async_request;
+use weather::async_request::client::HttpClient;
+use weather::keys::parse_keys;
 
 #[test]
 fn it_works() {
     let (weatherbit_key, apixu_key, owm_key) = check_keys();
 
-    let keys = weather::ApiKeys::new(owm_key, apixu_key, weatherbit_key);
-    let mut core = weather::start_server("0.0.0.0:13337", keys);
+    let keys = weather::ApiKeys::new(parse_keys(&owm_key), parse_keys(&apixu_key), parse_keys(&weatherbit_key));
+    let mut core = weather::start_server("0.0.0.0:13337", keys, weather::config::Config::default());
 
-    let response_future = async_request(&core.handle(), "http://0.0.0.0:13337/current?Tomsk").map(|f| {
+    let response_future = async_request(&HttpClient::new(&core.handle()), "http://0.0.0.0:13337/current?Tomsk").map(|f| {
         (f.status, f.body.iter().cloned().collect::<Vec<u8>>())
     });
 
@@ -29,10 +31,10 @@ fn it_works() {
 fn it_works_with_forecasts() {
     let (weatherbit_key, apixu_key, owm_key) = check_keys();
 
-    let keys = weather::ApiKeys::new(owm_key, apixu_key, weatherbit_key);
-    let mut core = weather::start_server("0.0.0.0:13337", keys);
+    let keys = weather::ApiKeys::new(parse_keys(&owm_key), parse_keys(&apixu_key), parse_keys(&weatherbit_key));
+    let mut core = weather::start_server("0.0.0.0:13337", keys, weather::config::Config::default());
 
-    let response_future = async_request(&core.handle(), "http://0.0.0.0:13337/forecast?Ufa").map(|f| {
+    let response_future = async_request(&HttpClient::new(&core.handle()), "http://0.0.0.0:13337/forecast?Ufa").map(|f| {
         (f.status, f.body.iter().cloned().collect::<Vec<u8>>())
     });
 
@@ -48,10 +50,10 @@ fn it_works_with_forecasts() {
 fn it_works_for_wrong_locations() {
     let (weatherbit_key, apixu_key, owm_key) = check_keys();
 
-    let keys = weather::ApiKeys::new(owm_key, apixu_key, weatherbit_key);
-    let mut core = weather::start_server("0.0.0.0:13337", keys);
+    let keys = weather::ApiKeys::new(parse_keys(&owm_key), parse_keys(&apixu_key), parse_keys(&weatherbit_key));
+    let mut core = weather::start_server("0.0.0.0:13337", keys, weather::config::Config::default());
 
-    let response_future = async_request(&core.handle(), "http://0.0.0.0:13337/current?Qwerty").map(|f| {
+    let response_future = async_request(&HttpClient::new(&core.handle()), "http://0.0.0.0:13337/current?Qwerty").map(|f| {
         (f.status, f.body.iter().cloned().collect::<Vec<u8>>())
     });
 
@@ -62,6 +64,25 @@ fn it_works_for_wrong_locations() {
     assert!(str_body.contains("Location not found"));
 }
 
+#[test]
+fn it_works_with_json() {
+    let (weatherbit_key, apixu_key, owm_key) = check_keys();
+
+    let keys = weather::ApiKeys::new(parse_keys(&owm_key), parse_keys(&apixu_key), parse_keys(&weatherbit_key));
+    let mut core = weather::start_server("0.0.0.0:13337", keys, weather::config::Config::default());
+
+    let response_future = async_request(&HttpClient::new(&core.handle()), "http://0.0.0.0:13337/current?Tomsk&format=json").map(|f| {
+        (f.status, f.body.iter().cloned().collect::<Vec<u8>>())
+    });
+
+    let (status, body) = core.run(response_future).unwrap();
+    let str_body = std::str::from_utf8(&body).unwrap();
+
+    assert_eq!(status, hyper::StatusCode::Ok);
+    assert!(str_body.contains(r#""current":{"#));
+    assert!(str_body.contains(r#""providers":["#));
+}
+
 
 fn check_keys() -> (String, String, String) {
     let weatherbit_key = match var("WEATHERBIT_KEY") {